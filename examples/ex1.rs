@@ -1,12 +1,11 @@
-use bevy::asset::LoadState;
 use bevy::ecs::system::{Resource, SystemParam};
 use bevy::input::keyboard::KeyboardInput;
 use bevy::input::mouse::{MouseButtonInput, MouseScrollUnit, MouseWheel};
 use bevy::input::ButtonState;
 use bevy::input::{mouse::MouseButton, Input};
 use bevy::prelude::{
-    warn, App, AssetServer, Camera2dBundle, Commands, DefaultPlugins, EventReader, Font, Handle,
-    Local, NonSend, NonSendMut, Res, ResMut, State, SystemSet, Windows,
+    warn, App, AssetServer, Camera2dBundle, Commands, DefaultPlugins, EventReader, Handle, Local,
+    NonSend, NonSendMut, Res, ResMut, Windows,
 };
 use bevy::time::Time;
 use bevy::utils::HashMap;
@@ -40,23 +39,20 @@ impl Root for SomeData {
 
 type DruidWindows<T> = HashMap<bevy::window::WindowId, druid::Window<T>>;
 
-// We have to pre-load fonts since Druid will panic if a text layout
-// fails to build.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum DruidState {
-    Loading,
-    Running,
-}
+/// The font asset for [`ui_font`].
+struct UiFont(Handle<bevy::text::Font>);
 
-#[derive(Default)]
-struct DruidFonts(Vec<Handle<Font>>);
+fn ui_font() -> FontDescriptor {
+    FontDescriptor::new(FontFamily::new_unchecked("Vollkorn")).with_size(15.0)
+}
 
 fn main() {
     let mut env = Env::with_default_i10n();
     env.set(
         druid::theme::UI_FONT,
-        // loaded from assets, system fonts don't work
-        FontDescriptor::new(FontFamily::new_unchecked("Vollkorn-Regular.ttf")).with_size(15.0),
+        // Vollkorn too, through the serif generic. Text is laid out
+        // with a stand-in until it loads. See `druid_font_system`.
+        FontDescriptor::new(FontFamily::SERIF).with_size(15.0),
     );
 
     App::new()
@@ -68,47 +64,34 @@ fn main() {
             group.disable::<bevy::ui::UiPlugin>()
         })
         .add_plugin(piet::PietPlugin::default())
-        .add_state(DruidState::Loading)
         .insert_non_send_resource(env)
         .insert_resource(SomeData("druid".to_string()))
         .insert_non_send_resource(DruidWindows::<SomeData>::default())
-        .insert_resource(DruidFonts::default())
+        .add_startup_system(setup)
         // ordering?
         .add_system(druid_window_system::<SomeData>)
         .add_system(druid_timer_system::<SomeData>)
-        .add_system_set(SystemSet::on_enter(DruidState::Loading).with_system(setup))
-        .add_system_set(SystemSet::on_update(DruidState::Loading).with_system(check_fonts))
-        .add_system_set(
-            SystemSet::on_update(DruidState::Running).with_system(druid_system::<SomeData>),
-        )
+        .add_system(druid_font_system)
+        .add_system(druid_system::<SomeData>)
         .run();
 }
 
 fn setup(
     mut commands: Commands,
-    mut druid_fonts: ResMut<DruidFonts>,
+    mut font_registry: ResMut<piet::FontRegistry>,
     asset_server: Res<AssetServer>,
 ) {
     commands.spawn_bundle(Camera2dBundle::default());
 
-    // Load all fonts or tie in w/ env?
-    //druid_fonts.0 = asset_server.load_folder(".").unwrap();
-    druid_fonts
-        .0
-        .push(asset_server.load("Vollkorn-Regular.ttf"))
-}
-
-// Remove DruidFonts resource?
-fn check_fonts(
-    mut state: ResMut<State<DruidState>>,
-    druid_fonts: Res<DruidFonts>,
-    asset_server: Res<AssetServer>,
-) {
-    match asset_server.get_group_load_state(druid_fonts.0.iter().map(|handle| handle.id)) {
-        LoadState::Failed => panic!("failed to load fonts"),
-        LoadState::Loaded => state.set(DruidState::Running).unwrap(),
-        _ => (), // loading
-    }
+    let font = asset_server.load("Vollkorn-Regular.ttf");
+    font_registry.register(
+        "Vollkorn",
+        FontWeight::REGULAR,
+        FontStyle::Regular,
+        font.clone(),
+    );
+    font_registry.set_generic(&FontFamily::SERIF, vec!["Vollkorn".to_string()]);
+    commands.insert_resource(UiFont(font));
 }
 
 // Going from winit -> Bevy -> Druid...
@@ -383,6 +366,24 @@ fn druid_timer_system<T: Data + Resource + Root>(
     }
 }
 
+// Widgets keep their text layouts, stand-in or not, until their font
+// in the env changes. Once Vollkorn has loaded, name it instead of the
+// generic; on the next update the widgets lay their text out again
+// and the windows repaint.
+fn druid_font_system(
+    mut env: NonSendMut<Env>,
+    font: Res<UiFont>,
+    mut font_events: EventReader<piet::FontEvent>,
+) {
+    for e in font_events.iter() {
+        if let piet::FontEvent::Loaded(handle) = e {
+            if *handle == font.0 {
+                env.set(druid::theme::UI_FONT, ui_font());
+            }
+        }
+    }
+}
+
 fn druid_system<T: Data + Resource + Root>(
     mut data: ResMut<T>,
     env: NonSend<Env>,
@@ -1,8 +1,8 @@
 use bevy::{prelude::*, window::WindowResized, winit::WinitSettings};
 
 use piet_b::{
-    self as piet, kurbo, FontFamily, Piet, PietImage, PietTextLayout, RenderContext, Text,
-    TextLayout, TextLayoutBuilder,
+    self as piet, kurbo, FontFamily, FontRegistry, FontStyle, FontWeight, Piet, PietImage,
+    PietTextLayout, RenderContext, Text, TextLayout, TextLayoutBuilder,
 };
 
 fn main() {
@@ -24,9 +24,15 @@ fn main() {
         .run();
 }
 
-fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>, mut fonts: ResMut<FontRegistry>) {
     commands.spawn_bundle(Camera2dBundle::default());
     commands.insert_resource(PietImage::from(asset_server.load("hatch.png")));
+    fonts.register(
+        "Vollkorn",
+        FontWeight::REGULAR,
+        FontStyle::Regular,
+        asset_server.load("Vollkorn-Regular.ttf"),
+    );
 }
 
 fn draw(
@@ -34,10 +40,17 @@ fn draw(
     mut layout: Local<Option<PietTextLayout>>,
     mut resized: EventReader<WindowResized>,
     mut cursor_moved: EventReader<CursorMoved>,
+    mut font_events: EventReader<piet::FontEvent>,
     mut hit_test_point: Local<Option<piet::HitTestPoint>>,
     params: piet::PietParams,
 ) {
     let mut redraw = resized.iter().next().is_some();
+    // Text is drawn with a stand-in font until Vollkorn loads.
+    redraw |= font_events
+        .iter()
+        .filter(|e| matches!(e, piet::FontEvent::Loaded(_)))
+        .count()
+        > 0;
     let window = params.text_params.windows.primary();
     let width = window.width() as f64;
     let height = window.height() as f64;
@@ -69,11 +82,11 @@ fn draw(
     if layout.is_none() || redraw {
         let scale_factor = window.scale_factor();
         let mut piet = Piet::new(params, height as f32);
-        let family = FontFamily::new_unchecked("Vollkorn-Regular.ttf");
+        let family = FontFamily::new_unchecked("Vollkorn");
         *layout = if let Ok(layout) = piet
             .text()
             .new_text_layout("Hello,\npiet. ")
-            .font(family.clone(), 64.0)
+            .font(family, 64.0)
             .build()
         {
             piet.clear(None, piet::Color::TRANSPARENT);
@@ -125,7 +138,8 @@ fn draw(
                 let layout2 = ctx
                     .text()
                     .new_text_layout("This text is clipped.")
-                    .font(family, 32.0)
+                    // Whichever sans-serif system font is installed.
+                    .font(FontFamily::SANS_SERIF, 32.0)
                     .build()
                     .unwrap();
 
@@ -145,7 +159,7 @@ fn draw(
 
             Some(layout)
         } else {
-            // font is still loading
+            // no fonts at all
             None
         }
     }
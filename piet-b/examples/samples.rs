@@ -0,0 +1,308 @@
+//! Runs piet's sample pictures through piet-b headless and reports
+//! which panic, which return errors, and how far the rest are from
+//! the reference images.
+//!
+//! cargo run --example samples -- [--out DIR] [--compare DIR] [NUMBER...]
+//!
+//! Reference images are matched by number (`*-NN.png`), e.g. the
+//! ones generated by piet-cairo's `test-picture` example. References
+//! drawn at an integer multiple of the sample size are scaled down
+//! before comparing.
+
+use bevy::{
+    ecs::system::SystemState,
+    prelude::*,
+    text::{FontAtlasSet, FontLoader},
+    window::Windows,
+};
+use piet_b::{
+    kurbo, samples, CanvasDepths, CanvasId, FontRegistry, NullRenderContext, PietCanvas,
+    PietParams, PietPlugin, Raster, RenderContext,
+};
+use std::{
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How long to wait for fonts before drawing with stand-ins.
+const FONT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// The most recent panic, with its location.
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+type BoxErr = Box<dyn std::error::Error>;
+
+enum Outcome {
+    Panicked(String),
+    Failed(piet_b::Error),
+    Drawn(Raster),
+}
+
+struct Args {
+    out_dir: PathBuf,
+    compare_dir: Option<PathBuf>,
+    numbers: Vec<usize>,
+}
+
+fn main() -> Result<(), BoxErr> {
+    let args = parse_args()?;
+    std::fs::create_dir_all(&args.out_dir)?;
+
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(bevy::asset::AssetPlugin)
+        .add_asset::<Image>()
+        .add_asset::<Font>()
+        .add_asset::<FontAtlasSet>()
+        .add_asset::<TextureAtlas>()
+        .init_asset_loader::<FontLoader>()
+        // No window; the scale factor is 1.
+        .init_resource::<Windows>();
+    PietPlugin::default().headless(&mut app);
+
+    panic::set_hook(Box::new(|info| {
+        *LAST_PANIC.lock().unwrap() = Some(info.to_string());
+    }));
+
+    let (mut panicked, mut failed, mut drawn) = (0, 0, 0);
+    for &number in &args.numbers {
+        print!("Sample {:02}: ", number);
+        match run_sample(&mut app, number) {
+            Outcome::Panicked(message) => {
+                panicked += 1;
+                println!("{}", message);
+            }
+            Outcome::Failed(e) => {
+                failed += 1;
+                println!("error: {}", e);
+            }
+            Outcome::Drawn(raster) => {
+                drawn += 1;
+                let path = args.out_dir.join(format!("piet-b-test-{:02}.png", number));
+                raster.save_png(&path)?;
+                match &args.compare_dir {
+                    Some(dir) => println!("{}", compare(dir, &args.out_dir, number, &raster)),
+                    None => println!("ok"),
+                }
+            }
+        }
+    }
+
+    let _ = panic::take_hook();
+    println!(
+        "{} drawn, {} returned errors, {} panicked",
+        drawn, failed, panicked
+    );
+    Ok(())
+}
+
+fn parse_args() -> Result<Args, BoxErr> {
+    let mut args = Args {
+        out_dir: PathBuf::from("target/piet-b-samples"),
+        compare_dir: None,
+        numbers: Vec::new(),
+    };
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--out" => args.out_dir = iter.next().ok_or("--out needs a directory")?.into(),
+            "--compare" => {
+                args.compare_dir = Some(iter.next().ok_or("--compare needs a directory")?.into())
+            }
+            number => {
+                let number: usize = number.parse()?;
+                if number >= samples::SAMPLE_COUNT {
+                    return Err(format!("no sample #{}", number).into());
+                }
+                args.numbers.push(number);
+            }
+        }
+    }
+    if args.numbers.is_empty() {
+        args.numbers = (0..samples::SAMPLE_COUNT).collect();
+    }
+    Ok(args)
+}
+
+fn run_sample(app: &mut App, number: usize) -> Outcome {
+    let size = samples::get::<NullRenderContext>(number)
+        .expect("sample number is checked")
+        .size();
+
+    // Each sample draws into its own canvas.
+    let canvas = CanvasId(number as u32);
+    app.world.spawn().insert(PietCanvas {
+        id: canvas,
+        rect: Some(kurbo::Rect::from_origin_size(kurbo::Point::ZERO, size)),
+        z: 0.0,
+    });
+
+    let deadline = Instant::now() + FONT_TIMEOUT;
+    loop {
+        let mut state = SystemState::<PietParams>::new(&mut app.world);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut piet = state
+                .get_mut(&mut app.world)
+                .piet_canvas(canvas, size.height as f32);
+            samples::get(number)
+                .expect("sample number is checked")
+                .draw(&mut piet)?;
+            piet.finish()
+        }));
+
+        match result {
+            Err(_) => {
+                let message = LAST_PANIC.lock().unwrap().take();
+                return Outcome::Panicked(message.unwrap_or_else(|| "panicked".into()));
+            }
+            // Fonts load asynchronously, and text is laid out with a
+            // stand-in until they do. Drop the commands and try again
+            // once they have had a chance to load.
+            Ok(Ok(()))
+                if app.world.resource::<FontRegistry>().is_loading()
+                    && Instant::now() < deadline =>
+            {
+                app.world.resource_mut::<CanvasDepths>().reset(canvas);
+                app.update();
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Ok(Err(e)) => return Outcome::Failed(e),
+            Ok(Ok(())) => {
+                state.apply(&mut app.world);
+                return Outcome::Drawn(piet_b::rasterize(&mut app.world, canvas, size, 1.0));
+            }
+        }
+    }
+}
+
+// Compare against the reference and write a difference image next
+// to the output.
+fn compare(compare_dir: &Path, out_dir: &Path, number: usize, raster: &Raster) -> String {
+    let reference = match find_reference(compare_dir, number) {
+        Some(path) => path,
+        None => return "no reference".into(),
+    };
+    let reference = match read_png(&reference) {
+        Ok(reference) => reference,
+        Err(e) => return format!("failed to read reference: {}", e),
+    };
+    let reference = match downscale(reference, raster.width, raster.height) {
+        Ok(reference) => reference,
+        Err((width, height)) => {
+            return format!(
+                "mismatched sizes, reference {}x{}, piet-b {}x{}",
+                width, height, raster.width, raster.height
+            )
+        }
+    };
+
+    let mut diff = Raster {
+        width: raster.width,
+        height: raster.height,
+        data: Vec::with_capacity(raster.data.len()),
+    };
+    let mut total = 0.0;
+    for (a, b) in reference.data.chunks(4).zip(raster.data.chunks(4)) {
+        let d = a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .sum::<i32>()
+            / 4;
+        total += d as f64;
+        // Make any difference visible.
+        let v = if d > 0 { d.max(24) as u8 } else { 0 };
+        diff.data.extend([v, v, v, 255]);
+    }
+
+    let pct = total / (raster.width * raster.height).max(1) as f64 / 255.0 * 100.0;
+    if total == 0.0 {
+        return "ok, identical".into();
+    }
+    let path = out_dir.join(format!("piet-b-test-{:02}-diff.png", number));
+    match diff.save_png(&path) {
+        Ok(()) => format!("ok, differs {:>5.2}%: {}", pct, path.display()),
+        Err(e) => format!("ok, differs {:>5.2}% (failed to save diff: {})", pct, e),
+    }
+}
+
+// Any png whose name ends in "-NN".
+fn find_reference(dir: &Path, number: usize) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |e| e == "png"))
+        .find(|path| {
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.rsplit('-').next())
+                .and_then(|n| n.parse::<usize>().ok())
+                == Some(number)
+        })
+}
+
+fn read_png(path: &Path) -> Result<Raster, BoxErr> {
+    let mut decoder = png::Decoder::new(std::fs::File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+
+    let data = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+        png::ColorType::Indexed => return Err("indexed color was not expanded".into()),
+    };
+    Ok(Raster {
+        width: info.width,
+        height: info.height,
+        data,
+    })
+}
+
+// Box filter down to `width` x `height`. Returns the reference size
+// if it isn't an integer multiple.
+fn downscale(raster: Raster, width: u32, height: u32) -> Result<Raster, (u32, u32)> {
+    let mismatch = (raster.width, raster.height);
+    if width == 0 || height == 0 || raster.width % width != 0 {
+        return Err(mismatch);
+    }
+    let k = raster.width / width;
+    if raster.height != height * k {
+        return Err(mismatch);
+    }
+    if k == 1 {
+        return Ok(raster);
+    }
+
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0u32; 4];
+            for dy in 0..k {
+                for dx in 0..k {
+                    let p = raster.pixel(x * k + dx, y * k + dy);
+                    for (s, c) in sum.iter_mut().zip(p) {
+                        *s += c as u32;
+                    }
+                }
+            }
+            data.extend(sum.map(|s| (s / (k * k)) as u8));
+        }
+    }
+    Ok(Raster {
+        width,
+        height,
+        data,
+    })
+}
@@ -0,0 +1,291 @@
+//! Text layouts kept across frames.
+
+use crate::{Attributes, FontRegistry, LineHeight, Overflow, PietTextLayout, TabStops};
+use bevy::{
+    prelude::{Res, ResMut},
+    utils::HashMap,
+};
+use std::{collections::BTreeMap, mem::Discriminant, ops::Range, sync::Arc};
+
+/// Everything a text layout depends on. Floats are kept as their bits,
+/// so equal keys hash the same, NaN included.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct LayoutKey {
+    pub text: Arc<str>,
+    pub runs: Vec<(Range<usize>, AttributesKey)>,
+    pub alignment: Discriminant<piet::TextAlignment>,
+    pub overflow: Overflow,
+    pub tab_stops: TabStopsKey,
+    pub max_width: u64,
+    pub scale_factor: u64,
+    /// The font registry's generation.
+    pub fonts: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct AttributesKey {
+    font: Option<piet::FontFamily>,
+    size: u64,
+    weight: piet::FontWeight,
+    style: piet::FontStyle,
+    color: u32,
+    underline: bool,
+    strikethrough: bool,
+    letter_spacing: u64,
+    line_height: (Discriminant<LineHeight>, u64),
+}
+
+impl From<&Attributes> for AttributesKey {
+    fn from(attrs: &Attributes) -> Self {
+        let height = match attrs.line_height {
+            LineHeight::Normal => 0,
+            LineHeight::Multiple(h) | LineHeight::Fixed(h) => h.to_bits(),
+        };
+        Self {
+            font: attrs.font.clone(),
+            size: attrs.size.to_bits(),
+            weight: attrs.weight,
+            style: attrs.style,
+            color: attrs.color.as_rgba_u32(),
+            underline: attrs.underline,
+            strikethrough: attrs.strikethrough,
+            letter_spacing: attrs.letter_spacing.to_bits(),
+            line_height: (std::mem::discriminant(&attrs.line_height), height),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum TabStopsKey {
+    Spaces(u32),
+    Positions(Vec<u64>),
+}
+
+impl From<&TabStops> for TabStopsKey {
+    fn from(tab_stops: &TabStops) -> Self {
+        match tab_stops {
+            TabStops::Spaces(n) => Self::Spaces(*n),
+            TabStops::Positions(stops) => {
+                Self::Positions(stops.iter().map(|s| s.to_bits()).collect())
+            }
+        }
+    }
+}
+
+/// Text layouts built in recent frames. Building a layout with the
+/// same text, attributes, alignment, width and scale factor returns
+/// the cached one without laying it out again, unless fonts have
+/// changed since.
+pub struct TextLayoutCache {
+    // Each layout with the frame and use count it was last used at.
+    layouts: HashMap<Arc<LayoutKey>, (PietTextLayout, u64, u64)>,
+    // Keys by use count, least recently used first.
+    recent: BTreeMap<u64, Arc<LayoutKey>>,
+    uses: u64,
+    frame: u64,
+    // The font registry generation the layouts were built with.
+    fonts: u64,
+    /// Layouts unused for this many frames are dropped.
+    pub max_age: u64,
+    /// The most layouts kept. The least recently used are dropped
+    /// first.
+    pub capacity: usize,
+}
+
+impl Default for TextLayoutCache {
+    fn default() -> Self {
+        Self {
+            layouts: HashMap::default(),
+            recent: BTreeMap::new(),
+            uses: 0,
+            frame: 0,
+            fonts: 0,
+            max_age: 60,
+            capacity: 1024,
+        }
+    }
+}
+
+impl TextLayoutCache {
+    pub(crate) fn get(&mut self, key: &LayoutKey) -> Option<PietTextLayout> {
+        let (layout, frame, uses) = self.layouts.get_mut(key)?;
+        let key = self.recent.remove(uses)?;
+        self.uses += 1;
+        *frame = self.frame;
+        *uses = self.uses;
+        self.recent.insert(self.uses, key);
+        Some(layout.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: LayoutKey, layout: PietTextLayout) {
+        if let Some((_, _, uses)) = self.layouts.remove(&key) {
+            self.recent.remove(&uses);
+        } else if self.layouts.len() >= self.capacity {
+            self.remove_least_recent();
+        }
+        let key = Arc::new(key);
+        self.uses += 1;
+        self.recent.insert(self.uses, key.clone());
+        self.layouts.insert(key, (layout, self.frame, self.uses));
+    }
+
+    fn remove_least_recent(&mut self) {
+        if let Some(uses) = self.recent.keys().next().copied() {
+            if let Some(key) = self.recent.remove(&uses) {
+                self.layouts.remove(&key);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.layouts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layouts.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.layouts.clear();
+        self.recent.clear();
+    }
+
+    /// Start the next frame, dropping layouts that have aged out.
+    pub fn next_frame(&mut self) {
+        self.frame += 1;
+        // The least recently used were also used the longest ago.
+        while let Some(key) = self.recent.values().next() {
+            let (_, used, _) = &self.layouts[key];
+            if self.frame - used <= self.max_age {
+                break;
+            }
+            self.remove_least_recent();
+        }
+    }
+}
+
+/// Ages the cache each frame. Layouts are dropped when the font
+/// registry changes; they can't be hit again after that anyway.
+pub fn age_text_layouts(mut cache: ResMut<TextLayoutCache>, registry: Res<FontRegistry>) {
+    if cache.fonts != registry.generation() {
+        cache.clear();
+        cache.fonts = registry.generation();
+    }
+    cache.next_frame();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(text: &str) -> LayoutKey {
+        LayoutKey {
+            text: text.into(),
+            runs: vec![(0..text.len(), (&Attributes::default()).into())],
+            alignment: std::mem::discriminant(&piet::TextAlignment::Start),
+            overflow: Overflow::default(),
+            tab_stops: (&TabStops::default()).into(),
+            max_width: f64::MAX.to_bits(),
+            scale_factor: 1f64.to_bits(),
+            fonts: 0,
+        }
+    }
+
+    fn layout(text: &str) -> PietTextLayout {
+        PietTextLayout::empty(text)
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let mut cache = TextLayoutCache::default();
+        cache.insert(key("a"), layout("a"));
+        assert_eq!(cache.get(&key("a")).unwrap().text.as_ref(), "a");
+
+        let mut k = key("a");
+        k.max_width = 100f64.to_bits();
+        assert!(cache.get(&k).is_none());
+        let mut k = key("a");
+        k.scale_factor = 2f64.to_bits();
+        assert!(cache.get(&k).is_none());
+        let mut k = key("a");
+        let attrs = Attributes {
+            size: 13.0,
+            ..Default::default()
+        };
+        k.runs[0].1 = (&attrs).into();
+        assert!(cache.get(&k).is_none());
+        let mut k = key("a");
+        k.fonts += 1;
+        assert!(cache.get(&k).is_none());
+    }
+
+    #[test]
+    fn float_keys() {
+        let mut cache = TextLayoutCache::default();
+        let spaced = |spacing: f64| {
+            let mut k = key("a");
+            let attrs = Attributes {
+                letter_spacing: spacing,
+                ..Default::default()
+            };
+            k.runs[0].1 = (&attrs).into();
+            k
+        };
+        // NaN keys still hit instead of piling up.
+        cache.insert(spaced(f64::NAN), layout("a"));
+        assert!(cache.get(&spaced(f64::NAN)).is_some());
+        cache.insert(spaced(f64::NAN), layout("a"));
+        assert_eq!(cache.len(), 1);
+        // Equal keys hash the same; -0 is its own key.
+        assert!(cache.get(&spaced(-0.0)).is_none());
+
+        let mut k = key("a");
+        k.tab_stops = (&TabStops::Positions(vec![f64::NAN])).into();
+        cache.insert(k.clone(), layout("a"));
+        assert!(cache.get(&k).is_some());
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut cache = TextLayoutCache {
+            capacity: 2,
+            ..Default::default()
+        };
+        cache.insert(key("a"), layout("a"));
+        cache.next_frame();
+        cache.insert(key("b"), layout("b"));
+        cache.next_frame();
+        // "a" was used more recently than "b".
+        assert!(cache.get(&key("a")).is_some());
+        cache.insert(key("c"), layout("c"));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+
+        // Within a frame too, and inserting a key again doesn't evict.
+        cache.insert(key("a"), layout("a"));
+        assert_eq!(cache.len(), 2);
+        cache.insert(key("d"), layout("d"));
+        assert!(cache.get(&key("c")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("d")).is_some());
+    }
+
+    #[test]
+    fn age_out() {
+        let mut cache = TextLayoutCache {
+            max_age: 2,
+            ..Default::default()
+        };
+        cache.insert(key("a"), layout("a"));
+        cache.insert(key("b"), layout("b"));
+        cache.next_frame();
+        cache.next_frame();
+        assert!(cache.get(&key("a")).is_some());
+        cache.next_frame();
+        // "b" is three frames old.
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key("b")).is_none());
+    }
+}
@@ -0,0 +1,1008 @@
+//! A [`RenderContext`] that records every call into a [`DisplayList`]
+//! instead of drawing. Display lists serialize with serde and can be
+//! replayed into any other context, e.g. a live [`Piet`](crate::Piet).
+
+use piet::kurbo::{self, Affine, BezPath, PathEl, Point, Rect, RoundedRect, Size, Vec2};
+use piet::{IntoBrush, RenderContext, Text as _, TextLayoutBuilder as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    ops::{Range, RangeBounds},
+    rc::Rc,
+    sync::Arc,
+};
+
+/// A recorded frame. Colors are stored as packed RGBA (see
+/// `Color::as_rgba_u32`).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DisplayList {
+    pub commands: Vec<Command>,
+    /// Images made with `make_image`, indexed by [`Command::DrawImage`].
+    pub images: Vec<ImageData>,
+    /// Text layouts, indexed by [`Command::DrawText`].
+    pub layouts: Vec<TextLayoutDesc>,
+    /// Font data passed to `load_font`. These are loaded before
+    /// anything else on replay.
+    pub fonts: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Command {
+    Clear {
+        region: Option<Rect>,
+        color: u32,
+    },
+    Stroke {
+        shape: Shape,
+        brush: Brush,
+        width: f64,
+        style: Option<StrokeStyle>,
+    },
+    Fill {
+        shape: Shape,
+        brush: Brush,
+    },
+    FillEvenOdd {
+        shape: Shape,
+        brush: Brush,
+    },
+    Clip(Shape),
+    DrawText {
+        layout: usize,
+        pos: Point,
+    },
+    Save,
+    Restore,
+    Transform(Affine),
+    DrawImage {
+        image: usize,
+        dst: Rect,
+        interp: Interpolation,
+    },
+    DrawImageArea {
+        image: usize,
+        src: Rect,
+        dst: Rect,
+        interp: Interpolation,
+    },
+    BlurredRect {
+        rect: Rect,
+        blur_radius: f64,
+        brush: Brush,
+    },
+}
+
+/// Rects and rounded rects are kept as-is since some backends
+/// (piet-b) only handle those; everything else is flattened to a
+/// path.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Shape {
+    Rect(Rect),
+    RoundedRect(RoundedRect),
+    Path(BezPath),
+}
+
+impl Shape {
+    fn new(shape: impl kurbo::Shape) -> Self {
+        if let Some(rect) = shape.as_rect() {
+            Shape::Rect(rect)
+        } else if let Some(rect) = shape.as_rounded_rect() {
+            Shape::RoundedRect(rect)
+        } else {
+            Shape::Path(shape.into_path(0.1))
+        }
+    }
+}
+
+impl kurbo::Shape for Shape {
+    type PathElementsIter = std::vec::IntoIter<PathEl>;
+
+    fn path_elements(&self, tolerance: f64) -> Self::PathElementsIter {
+        match self {
+            Shape::Rect(r) => r.path_elements(tolerance).collect::<Vec<_>>(),
+            Shape::RoundedRect(r) => r.path_elements(tolerance).collect(),
+            Shape::Path(p) => p.elements().to_vec(),
+        }
+        .into_iter()
+    }
+
+    fn area(&self) -> f64 {
+        match self {
+            Shape::Rect(r) => r.area(),
+            Shape::RoundedRect(r) => r.area(),
+            Shape::Path(p) => p.area(),
+        }
+    }
+
+    fn perimeter(&self, accuracy: f64) -> f64 {
+        match self {
+            Shape::Rect(r) => r.perimeter(accuracy),
+            Shape::RoundedRect(r) => r.perimeter(accuracy),
+            Shape::Path(p) => p.perimeter(accuracy),
+        }
+    }
+
+    fn winding(&self, pt: Point) -> i32 {
+        match self {
+            Shape::Rect(r) => r.winding(pt),
+            Shape::RoundedRect(r) => r.winding(pt),
+            Shape::Path(p) => p.winding(pt),
+        }
+    }
+
+    fn bounding_box(&self) -> Rect {
+        match self {
+            Shape::Rect(r) => r.bounding_box(),
+            Shape::RoundedRect(r) => r.bounding_box(),
+            Shape::Path(p) => p.bounding_box(),
+        }
+    }
+
+    fn as_rect(&self) -> Option<Rect> {
+        match self {
+            Shape::Rect(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    fn as_rounded_rect(&self) -> Option<RoundedRect> {
+        match self {
+            Shape::RoundedRect(r) => Some(*r),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Brush {
+    Solid(u32),
+    Linear {
+        start: Point,
+        end: Point,
+        stops: Vec<(f32, u32)>,
+    },
+    Radial {
+        center: Point,
+        origin_offset: Vec2,
+        radius: f64,
+        stops: Vec<(f32, u32)>,
+    },
+}
+
+fn stops_from_piet(stops: &[piet::GradientStop]) -> Vec<(f32, u32)> {
+    stops
+        .iter()
+        .map(|s| (s.pos, s.color.as_rgba_u32()))
+        .collect()
+}
+
+fn stops_to_piet(stops: &[(f32, u32)]) -> Vec<piet::GradientStop> {
+    stops
+        .iter()
+        .map(|(pos, color)| piet::GradientStop {
+            pos: *pos,
+            color: piet::Color::from_rgba32_u32(*color),
+        })
+        .collect()
+}
+
+impl From<piet::FixedGradient> for Brush {
+    fn from(gradient: piet::FixedGradient) -> Self {
+        match gradient {
+            piet::FixedGradient::Linear(g) => Brush::Linear {
+                start: g.start,
+                end: g.end,
+                stops: stops_from_piet(&g.stops),
+            },
+            piet::FixedGradient::Radial(g) => Brush::Radial {
+                center: g.center,
+                origin_offset: g.origin_offset,
+                radius: g.radius,
+                stops: stops_from_piet(&g.stops),
+            },
+        }
+    }
+}
+
+impl Brush {
+    fn make<R: RenderContext>(&self, ctx: &mut R) -> Result<R::Brush, piet::Error> {
+        match self {
+            Brush::Solid(color) => Ok(ctx.solid_brush(piet::Color::from_rgba32_u32(*color))),
+            Brush::Linear { start, end, stops } => {
+                ctx.gradient(piet::FixedGradient::Linear(piet::FixedLinearGradient {
+                    start: *start,
+                    end: *end,
+                    stops: stops_to_piet(stops),
+                }))
+            }
+            Brush::Radial {
+                center,
+                origin_offset,
+                radius,
+                stops,
+            } => ctx.gradient(piet::FixedGradient::Radial(piet::FixedRadialGradient {
+                center: *center,
+                origin_offset: *origin_offset,
+                radius: *radius,
+                stops: stops_to_piet(stops),
+            })),
+        }
+    }
+}
+
+impl<T: piet::Text> IntoBrush<Recorder<T>> for Brush {
+    fn make_brush<'b>(
+        &'b self,
+        _piet: &mut Recorder<T>,
+        _bbox: impl FnOnce() -> Rect,
+    ) -> Cow<'b, Brush> {
+        Cow::Borrowed(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum LineJoin {
+    Miter { limit: f64 },
+    Round,
+    Bevel,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StrokeStyle {
+    pub line_join: LineJoin,
+    pub line_cap: LineCap,
+    pub dash_pattern: Vec<f64>,
+    pub dash_offset: f64,
+}
+
+impl From<&piet::StrokeStyle> for StrokeStyle {
+    fn from(style: &piet::StrokeStyle) -> Self {
+        Self {
+            line_join: match style.line_join {
+                piet::LineJoin::Miter { limit } => LineJoin::Miter { limit },
+                piet::LineJoin::Round => LineJoin::Round,
+                piet::LineJoin::Bevel => LineJoin::Bevel,
+            },
+            line_cap: match style.line_cap {
+                piet::LineCap::Butt => LineCap::Butt,
+                piet::LineCap::Round => LineCap::Round,
+                piet::LineCap::Square => LineCap::Square,
+            },
+            dash_pattern: style.dash_pattern.to_vec(),
+            dash_offset: style.dash_offset,
+        }
+    }
+}
+
+impl From<&StrokeStyle> for piet::StrokeStyle {
+    fn from(style: &StrokeStyle) -> Self {
+        let mut stroke_style = piet::StrokeStyle::new()
+            .line_join(match style.line_join {
+                LineJoin::Miter { limit } => piet::LineJoin::Miter { limit },
+                LineJoin::Round => piet::LineJoin::Round,
+                LineJoin::Bevel => piet::LineJoin::Bevel,
+            })
+            .line_cap(match style.line_cap {
+                LineCap::Butt => piet::LineCap::Butt,
+                LineCap::Round => piet::LineCap::Round,
+                LineCap::Square => piet::LineCap::Square,
+            })
+            .dash_offset(style.dash_offset);
+        stroke_style.set_dash_pattern(style.dash_pattern.as_slice());
+        stroke_style
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Interpolation {
+    NearestNeighbor,
+    Bilinear,
+}
+
+impl From<piet::InterpolationMode> for Interpolation {
+    fn from(interp: piet::InterpolationMode) -> Self {
+        match interp {
+            piet::InterpolationMode::NearestNeighbor => Interpolation::NearestNeighbor,
+            piet::InterpolationMode::Bilinear => Interpolation::Bilinear,
+        }
+    }
+}
+
+impl From<Interpolation> for piet::InterpolationMode {
+    fn from(interp: Interpolation) -> Self {
+        match interp {
+            Interpolation::NearestNeighbor => piet::InterpolationMode::NearestNeighbor,
+            Interpolation::Bilinear => piet::InterpolationMode::Bilinear,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ImageFormat {
+    Grayscale,
+    Rgb,
+    RgbaSeparate,
+    RgbaPremul,
+}
+
+impl ImageFormat {
+    fn from_piet(format: piet::ImageFormat) -> Option<Self> {
+        match format {
+            piet::ImageFormat::Grayscale => Some(ImageFormat::Grayscale),
+            piet::ImageFormat::Rgb => Some(ImageFormat::Rgb),
+            piet::ImageFormat::RgbaSeparate => Some(ImageFormat::RgbaSeparate),
+            piet::ImageFormat::RgbaPremul => Some(ImageFormat::RgbaPremul),
+            _ => None,
+        }
+    }
+}
+
+impl From<ImageFormat> for piet::ImageFormat {
+    fn from(format: ImageFormat) -> Self {
+        match format {
+            ImageFormat::Grayscale => piet::ImageFormat::Grayscale,
+            ImageFormat::Rgb => piet::ImageFormat::Rgb,
+            ImageFormat::RgbaSeparate => piet::ImageFormat::RgbaSeparate,
+            ImageFormat::RgbaPremul => piet::ImageFormat::RgbaPremul,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageData {
+    pub width: usize,
+    pub height: usize,
+    pub format: ImageFormat,
+    pub buf: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Family {
+    Serif,
+    SansSerif,
+    Monospace,
+    SystemUi,
+    Named(String),
+}
+
+impl From<&piet::FontFamily> for Family {
+    fn from(family: &piet::FontFamily) -> Self {
+        match family.inner() {
+            piet::FontFamilyInner::Serif => Family::Serif,
+            piet::FontFamilyInner::SansSerif => Family::SansSerif,
+            piet::FontFamilyInner::Monospace => Family::Monospace,
+            piet::FontFamilyInner::SystemUi => Family::SystemUi,
+            _ => Family::Named(family.name().to_string()),
+        }
+    }
+}
+
+impl Family {
+    fn resolve(&self, text: &mut impl piet::Text) -> piet::FontFamily {
+        match self {
+            Family::Serif => piet::FontFamily::SERIF,
+            Family::SansSerif => piet::FontFamily::SANS_SERIF,
+            Family::Monospace => piet::FontFamily::MONOSPACE,
+            Family::SystemUi => piet::FontFamily::SYSTEM_UI,
+            Family::Named(name) => text
+                .font_family(name)
+                .unwrap_or_else(|| piet::FontFamily::new_unchecked(name.as_str())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Attribute {
+    FontFamily(Family),
+    FontSize(f64),
+    Weight(u16),
+    TextColor(u32),
+    Italic(bool),
+    Underline(bool),
+    Strikethrough(bool),
+}
+
+impl From<&piet::TextAttribute> for Attribute {
+    fn from(attr: &piet::TextAttribute) -> Self {
+        match *attr {
+            piet::TextAttribute::FontFamily(ref f) => Attribute::FontFamily(f.into()),
+            piet::TextAttribute::FontSize(size) => Attribute::FontSize(size),
+            piet::TextAttribute::Weight(w) => Attribute::Weight(w.to_raw()),
+            piet::TextAttribute::TextColor(ref c) => Attribute::TextColor(c.as_rgba_u32()),
+            piet::TextAttribute::Style(s) => Attribute::Italic(s == piet::FontStyle::Italic),
+            piet::TextAttribute::Underline(u) => Attribute::Underline(u),
+            piet::TextAttribute::Strikethrough(s) => Attribute::Strikethrough(s),
+        }
+    }
+}
+
+impl Attribute {
+    fn resolve(&self, text: &mut impl piet::Text) -> piet::TextAttribute {
+        match self {
+            Attribute::FontFamily(f) => piet::TextAttribute::FontFamily(f.resolve(text)),
+            Attribute::FontSize(size) => piet::TextAttribute::FontSize(*size),
+            Attribute::Weight(w) => piet::TextAttribute::Weight(piet::FontWeight::new(*w)),
+            Attribute::TextColor(c) => {
+                piet::TextAttribute::TextColor(piet::Color::from_rgba32_u32(*c))
+            }
+            Attribute::Italic(italic) => piet::TextAttribute::Style(if *italic {
+                piet::FontStyle::Italic
+            } else {
+                piet::FontStyle::Regular
+            }),
+            Attribute::Underline(u) => piet::TextAttribute::Underline(*u),
+            Attribute::Strikethrough(s) => piet::TextAttribute::Strikethrough(*s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Alignment {
+    Start,
+    End,
+    Center,
+    Justified,
+}
+
+impl From<piet::TextAlignment> for Alignment {
+    fn from(alignment: piet::TextAlignment) -> Self {
+        match alignment {
+            piet::TextAlignment::Start => Alignment::Start,
+            piet::TextAlignment::End => Alignment::End,
+            piet::TextAlignment::Center => Alignment::Center,
+            piet::TextAlignment::Justified => Alignment::Justified,
+        }
+    }
+}
+
+impl From<Alignment> for piet::TextAlignment {
+    fn from(alignment: Alignment) -> Self {
+        match alignment {
+            Alignment::Start => piet::TextAlignment::Start,
+            Alignment::End => piet::TextAlignment::End,
+            Alignment::Center => piet::TextAlignment::Center,
+            Alignment::Justified => piet::TextAlignment::Justified,
+        }
+    }
+}
+
+/// Everything passed to a text layout builder, in call order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TextLayoutDesc {
+    pub text: String,
+    /// `None` is unbounded.
+    pub max_width: Option<f64>,
+    pub alignment: Alignment,
+    pub default_attributes: Vec<Attribute>,
+    pub range_attributes: Vec<(Range<usize>, Attribute)>,
+}
+
+impl TextLayoutDesc {
+    /// Rebuild this layout with another `Text`.
+    pub fn build<T: piet::Text>(&self, text: &mut T) -> Result<T::TextLayout, piet::Error> {
+        let mut builder = text
+            .new_text_layout(self.text.clone())
+            .alignment(self.alignment.into());
+        if let Some(width) = self.max_width {
+            builder = builder.max_width(width);
+        }
+        for attr in &self.default_attributes {
+            builder = builder.default_attribute(attr.resolve(text));
+        }
+        for (range, attr) in &self.range_attributes {
+            builder = builder.range_attribute(range.clone(), attr.resolve(text));
+        }
+        builder.build()
+    }
+}
+
+impl DisplayList {
+    /// Replay all commands into `ctx`. Fonts, images and text layouts
+    /// are recreated in `ctx` first.
+    pub fn replay<R: RenderContext>(&self, ctx: &mut R) -> Result<(), piet::Error> {
+        for font in &self.fonts {
+            ctx.text().load_font(font)?;
+        }
+
+        let images = self
+            .images
+            .iter()
+            .map(|i| ctx.make_image(i.width, i.height, &i.buf, i.format.into()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let layouts = self
+            .layouts
+            .iter()
+            .map(|l| l.build(ctx.text()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for command in &self.commands {
+            match command {
+                Command::Clear { region, color } => {
+                    ctx.clear(*region, piet::Color::from_rgba32_u32(*color))
+                }
+                Command::Stroke {
+                    shape,
+                    brush,
+                    width,
+                    style,
+                } => {
+                    let brush = brush.make(ctx)?;
+                    match style {
+                        Some(style) => ctx.stroke_styled(shape, &brush, *width, &style.into()),
+                        None => ctx.stroke(shape, &brush, *width),
+                    }
+                }
+                Command::Fill { shape, brush } => {
+                    let brush = brush.make(ctx)?;
+                    ctx.fill(shape, &brush)
+                }
+                Command::FillEvenOdd { shape, brush } => {
+                    let brush = brush.make(ctx)?;
+                    ctx.fill_even_odd(shape, &brush)
+                }
+                Command::Clip(shape) => ctx.clip(shape),
+                Command::DrawText { layout, pos } => {
+                    ctx.draw_text(layouts.get(*layout).ok_or(piet::Error::InvalidInput)?, *pos)
+                }
+                Command::Save => ctx.save()?,
+                Command::Restore => ctx.restore()?,
+                Command::Transform(affine) => ctx.transform(*affine),
+                Command::DrawImage { image, dst, interp } => ctx.draw_image(
+                    images.get(*image).ok_or(piet::Error::InvalidInput)?,
+                    *dst,
+                    (*interp).into(),
+                ),
+                Command::DrawImageArea {
+                    image,
+                    src,
+                    dst,
+                    interp,
+                } => ctx.draw_image_area(
+                    images.get(*image).ok_or(piet::Error::InvalidInput)?,
+                    *src,
+                    *dst,
+                    (*interp).into(),
+                ),
+                Command::BlurredRect {
+                    rect,
+                    blur_radius,
+                    brush,
+                } => {
+                    let brush = brush.make(ctx)?;
+                    ctx.blurred_rect(*rect, *blur_radius, &brush)
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Records draw calls into a [`DisplayList`]. Text layouts are
+/// measured with the wrapped `Text` so widgets can still query sizes
+/// and hit test while recording.
+pub struct Recorder<T: piet::Text> {
+    list: DisplayList,
+    text: RecorderText<T>,
+    // Layouts already in `list.layouts`; draws of the same layout
+    // share an index.
+    layouts: Vec<Arc<TextLayoutDesc>>,
+    transform: Affine,
+    transform_stack: Vec<Affine>,
+}
+
+impl<T: piet::Text> Recorder<T> {
+    pub fn new(text: T) -> Self {
+        Self {
+            list: DisplayList::default(),
+            text: RecorderText {
+                inner: text,
+                fonts: Default::default(),
+            },
+            layouts: Vec::new(),
+            transform: Affine::IDENTITY,
+            transform_stack: Vec::new(),
+        }
+    }
+
+    /// The display list recorded so far.
+    pub fn display_list(&self) -> DisplayList {
+        let mut list = self.list.clone();
+        list.fonts = self.text.fonts.borrow().clone();
+        list
+    }
+
+    pub fn into_display_list(self) -> DisplayList {
+        let mut list = self.list;
+        list.fonts = self.text.fonts.take();
+        list
+    }
+
+    fn push(&mut self, command: Command) {
+        self.list.commands.push(command);
+    }
+
+    fn layout_index(&mut self, desc: &Arc<TextLayoutDesc>) -> usize {
+        match self.layouts.iter().position(|d| Arc::ptr_eq(d, desc)) {
+            Some(i) => i,
+            None => {
+                self.layouts.push(desc.clone());
+                self.list.layouts.push((**desc).clone());
+                self.list.layouts.len() - 1
+            }
+        }
+    }
+}
+
+impl<T: piet::Text> RenderContext for Recorder<T> {
+    type Brush = Brush;
+    type Text = RecorderText<T>;
+    type TextLayout = RecordedTextLayout<T::TextLayout>;
+    type Image = RecordedImage;
+
+    fn status(&mut self) -> Result<(), piet::Error> {
+        Ok(())
+    }
+
+    fn solid_brush(&mut self, color: piet::Color) -> Self::Brush {
+        Brush::Solid(color.as_rgba_u32())
+    }
+
+    fn gradient(
+        &mut self,
+        gradient: impl Into<piet::FixedGradient>,
+    ) -> Result<Self::Brush, piet::Error> {
+        Ok(gradient.into().into())
+    }
+
+    fn clear(&mut self, region: impl Into<Option<Rect>>, color: piet::Color) {
+        self.push(Command::Clear {
+            region: region.into(),
+            color: color.as_rgba_u32(),
+        })
+    }
+
+    fn stroke(&mut self, shape: impl kurbo::Shape, brush: &impl IntoBrush<Self>, width: f64) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        self.push(Command::Stroke {
+            shape: Shape::new(shape),
+            brush,
+            width,
+            style: None,
+        })
+    }
+
+    fn stroke_styled(
+        &mut self,
+        shape: impl kurbo::Shape,
+        brush: &impl IntoBrush<Self>,
+        width: f64,
+        style: &piet::StrokeStyle,
+    ) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        self.push(Command::Stroke {
+            shape: Shape::new(shape),
+            brush,
+            width,
+            style: Some(style.into()),
+        })
+    }
+
+    fn fill(&mut self, shape: impl kurbo::Shape, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        self.push(Command::Fill {
+            shape: Shape::new(shape),
+            brush,
+        })
+    }
+
+    fn fill_even_odd(&mut self, shape: impl kurbo::Shape, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        self.push(Command::FillEvenOdd {
+            shape: Shape::new(shape),
+            brush,
+        })
+    }
+
+    fn clip(&mut self, shape: impl kurbo::Shape) {
+        self.push(Command::Clip(Shape::new(shape)))
+    }
+
+    fn text(&mut self) -> &mut Self::Text {
+        &mut self.text
+    }
+
+    fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<Point>) {
+        let layout = self.layout_index(&layout.desc);
+        self.push(Command::DrawText {
+            layout,
+            pos: pos.into(),
+        })
+    }
+
+    fn save(&mut self) -> Result<(), piet::Error> {
+        self.transform_stack.push(self.transform);
+        self.push(Command::Save);
+        Ok(())
+    }
+
+    fn restore(&mut self) -> Result<(), piet::Error> {
+        if let Some(transform) = self.transform_stack.pop() {
+            self.transform = transform;
+            self.push(Command::Restore);
+            Ok(())
+        } else {
+            Err(piet::Error::StackUnbalance)
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), piet::Error> {
+        Ok(())
+    }
+
+    fn transform(&mut self, transform: Affine) {
+        self.transform *= transform;
+        self.push(Command::Transform(transform))
+    }
+
+    fn make_image(
+        &mut self,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: piet::ImageFormat,
+    ) -> Result<Self::Image, piet::Error> {
+        let format = ImageFormat::from_piet(format).ok_or(piet::Error::NotSupported)?;
+        self.list.images.push(ImageData {
+            width,
+            height,
+            format,
+            buf: buf.to_vec(),
+        });
+        Ok(RecordedImage {
+            id: self.list.images.len() - 1,
+            size: Size::new(width as f64, height as f64),
+        })
+    }
+
+    fn draw_image(
+        &mut self,
+        image: &Self::Image,
+        dst_rect: impl Into<Rect>,
+        interp: piet::InterpolationMode,
+    ) {
+        self.push(Command::DrawImage {
+            image: image.id,
+            dst: dst_rect.into(),
+            interp: interp.into(),
+        })
+    }
+
+    fn draw_image_area(
+        &mut self,
+        image: &Self::Image,
+        src_rect: impl Into<Rect>,
+        dst_rect: impl Into<Rect>,
+        interp: piet::InterpolationMode,
+    ) {
+        self.push(Command::DrawImageArea {
+            image: image.id,
+            src: src_rect.into(),
+            dst: dst_rect.into(),
+            interp: interp.into(),
+        })
+    }
+
+    // There is nothing to capture from.
+    fn capture_image_area(
+        &mut self,
+        _src_rect: impl Into<Rect>,
+    ) -> Result<Self::Image, piet::Error> {
+        Err(piet::Error::NotSupported)
+    }
+
+    fn blurred_rect(&mut self, rect: Rect, blur_radius: f64, brush: &impl IntoBrush<Self>) {
+        let brush = brush.make_brush(self, || rect).into_owned();
+        self.push(Command::BlurredRect {
+            rect,
+            blur_radius,
+            brush,
+        })
+    }
+
+    fn current_transform(&self) -> Affine {
+        self.transform
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordedImage {
+    id: usize,
+    size: Size,
+}
+
+impl piet::Image for RecordedImage {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+#[derive(Clone)]
+pub struct RecorderText<T> {
+    inner: T,
+    fonts: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl<T: piet::Text> piet::Text for RecorderText<T> {
+    type TextLayoutBuilder = RecorderTextLayoutBuilder<T>;
+    type TextLayout = RecordedTextLayout<T::TextLayout>;
+
+    fn font_family(&mut self, family_name: &str) -> Option<piet::FontFamily> {
+        self.inner.font_family(family_name)
+    }
+
+    fn load_font(&mut self, data: &[u8]) -> Result<piet::FontFamily, piet::Error> {
+        let family = self.inner.load_font(data)?;
+        self.fonts.borrow_mut().push(data.to_vec());
+        Ok(family)
+    }
+
+    fn new_text_layout(&mut self, text: impl piet::TextStorage) -> Self::TextLayoutBuilder {
+        RecorderTextLayoutBuilder {
+            desc: TextLayoutDesc {
+                text: text.as_str().to_string(),
+                max_width: None,
+                alignment: Alignment::Start,
+                default_attributes: Vec::new(),
+                range_attributes: Vec::new(),
+            },
+            inner: self.inner.new_text_layout(text),
+        }
+    }
+}
+
+pub struct RecorderTextLayoutBuilder<T: piet::Text> {
+    desc: TextLayoutDesc,
+    inner: T::TextLayoutBuilder,
+}
+
+impl<T: piet::Text> piet::TextLayoutBuilder for RecorderTextLayoutBuilder<T> {
+    type Out = RecordedTextLayout<T::TextLayout>;
+
+    fn max_width(mut self, width: f64) -> Self {
+        self.desc.max_width = Some(width).filter(|w| w.is_finite());
+        self.inner = self.inner.max_width(width);
+        self
+    }
+
+    fn alignment(mut self, alignment: piet::TextAlignment) -> Self {
+        self.desc.alignment = alignment.into();
+        self.inner = self.inner.alignment(alignment);
+        self
+    }
+
+    fn default_attribute(mut self, attr: impl Into<piet::TextAttribute>) -> Self {
+        let attr = attr.into();
+        self.desc.default_attributes.push((&attr).into());
+        self.inner = self.inner.default_attribute(attr);
+        self
+    }
+
+    fn range_attribute(
+        mut self,
+        range: impl RangeBounds<usize>,
+        attr: impl Into<piet::TextAttribute>,
+    ) -> Self {
+        let range = piet::util::resolve_range(range, self.desc.text.len());
+        let attr = attr.into();
+        self.desc
+            .range_attributes
+            .push((range.clone(), (&attr).into()));
+        self.inner = self.inner.range_attribute(range, attr);
+        self
+    }
+
+    fn build(self) -> Result<Self::Out, piet::Error> {
+        Ok(RecordedTextLayout {
+            desc: Arc::new(self.desc),
+            inner: self.inner.build()?,
+        })
+    }
+}
+
+/// A layout built by the wrapped `Text` along with the calls that
+/// built it.
+#[derive(Clone)]
+pub struct RecordedTextLayout<L> {
+    desc: Arc<TextLayoutDesc>,
+    inner: L,
+}
+
+impl<L> RecordedTextLayout<L> {
+    pub fn desc(&self) -> &TextLayoutDesc {
+        &self.desc
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+}
+
+impl<L: piet::TextLayout> piet::TextLayout for RecordedTextLayout<L> {
+    fn size(&self) -> Size {
+        self.inner.size()
+    }
+
+    fn trailing_whitespace_width(&self) -> f64 {
+        self.inner.trailing_whitespace_width()
+    }
+
+    fn image_bounds(&self) -> Rect {
+        self.inner.image_bounds()
+    }
+
+    fn text(&self) -> &str {
+        self.inner.text()
+    }
+
+    fn line_text(&self, line_number: usize) -> Option<&str> {
+        self.inner.line_text(line_number)
+    }
+
+    fn line_metric(&self, line_number: usize) -> Option<piet::LineMetric> {
+        self.inner.line_metric(line_number)
+    }
+
+    fn line_count(&self) -> usize {
+        self.inner.line_count()
+    }
+
+    fn hit_test_point(&self, point: Point) -> piet::HitTestPoint {
+        self.inner.hit_test_point(point)
+    }
+
+    fn hit_test_text_position(&self, idx: usize) -> piet::HitTestPosition {
+        self.inner.hit_test_text_position(idx)
+    }
+
+    fn rects_for_range(&self, range: impl RangeBounds<usize>) -> Vec<Rect> {
+        self.inner.rects_for_range(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use piet::{NullRenderContext, TextLayoutBuilder};
+
+    #[test]
+    fn round_trip() {
+        let mut recorder = Recorder::new(NullRenderContext::new().text().clone());
+        recorder.fill(Rect::new(0.0, 0.0, 10.0, 10.0), &piet::Color::RED);
+        let layout = recorder
+            .text()
+            .new_text_layout("text")
+            .text_color(piet::Color::BLUE)
+            .build()
+            .unwrap();
+        recorder.draw_text(&layout, (1.0, 2.0));
+        recorder.draw_text(&layout, (3.0, 4.0));
+
+        let list = recorder.into_display_list();
+        assert_eq!(list.commands.len(), 3);
+        assert_eq!(list.layouts.len(), 1);
+
+        let json = serde_json::to_string(&list).unwrap();
+        let list: DisplayList = serde_json::from_str(&json).unwrap();
+        list.replay(&mut NullRenderContext::new()).unwrap();
+    }
+}
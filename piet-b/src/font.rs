@@ -0,0 +1,1022 @@
+//! Font families. Faces are registered under a family name with a
+//! weight and style, and layouts resolve to the nearest face. Generic
+//! families map to the first installed family in a preference list.
+
+use bevy::{
+    asset::{AssetEvent, FileAssetIo, LoadState},
+    log::warn,
+    prelude::{AssetServer, Assets, EventReader, EventWriter, Handle, Res, ResMut},
+    text::Font,
+    utils::{HashMap, HashSet},
+};
+use glyph_brush_layout::ab_glyph::{self, FontArc, FontVec};
+use piet::{FontStyle, FontWeight};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+use ttf_parser::name_id;
+
+/// The font for text without a family: Anaheim Regular, under the SIL
+/// Open Font License (`fonts/OFL.txt`). It has a single face, so bold
+/// and italic text use it as is.
+pub const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/Anaheim-Regular.ttf");
+
+#[derive(Clone, Debug)]
+pub struct FontFace {
+    pub weight: FontWeight,
+    pub style: FontStyle,
+    pub source: FaceSource,
+}
+
+#[derive(Clone, Debug)]
+pub enum FaceSource {
+    Asset(Handle<Font>),
+    /// Not loaded until it is first used.
+    File {
+        path: PathBuf,
+        index: u32,
+    },
+}
+
+/// Maps family names to font faces. Names are case-insensitive.
+#[derive(Debug)]
+pub struct FontRegistry {
+    families: HashMap<String, (String, Vec<FontFace>)>,
+    // Generic family name to preferred families.
+    generics: HashMap<String, Vec<String>>,
+    // Families tried for characters the chosen font lacks.
+    fallback: Vec<String>,
+    // Read from the font's post and OS/2 tables.
+    decorations: HashMap<Handle<Font>, Decorations>,
+    // Fonts that layouts used a stand-in for while they loaded.
+    pending: Vec<Handle<Font>>,
+    failed: HashSet<Handle<Font>>,
+    // DEFAULT_FONT, once it's used.
+    default: Option<Handle<Font>>,
+    // Bumped whenever layouts could come out differently.
+    generation: u64,
+}
+
+impl Default for FontRegistry {
+    fn default() -> Self {
+        let generics = [
+            (
+                piet::FontFamily::SANS_SERIF,
+                &[
+                    "DejaVu Sans",
+                    "Noto Sans",
+                    "Liberation Sans",
+                    "Cantarell",
+                    "Ubuntu",
+                    "Roboto",
+                    "Arial",
+                ][..],
+            ),
+            (
+                piet::FontFamily::SERIF,
+                &[
+                    "DejaVu Serif",
+                    "Noto Serif",
+                    "Liberation Serif",
+                    "Times New Roman",
+                ],
+            ),
+            (
+                piet::FontFamily::MONOSPACE,
+                &[
+                    "DejaVu Sans Mono",
+                    "Noto Sans Mono",
+                    "Liberation Mono",
+                    "Ubuntu Mono",
+                    "Courier New",
+                ],
+            ),
+            (
+                piet::FontFamily::SYSTEM_UI,
+                &[
+                    "Cantarell",
+                    "Ubuntu",
+                    "Noto Sans",
+                    "DejaVu Sans",
+                    "Liberation Sans",
+                ],
+            ),
+        ]
+        .into_iter()
+        .map(|(generic, families)| {
+            (
+                generic.name().to_string(),
+                families.iter().map(|f| f.to_string()).collect(),
+            )
+        })
+        .collect();
+
+        let fallback = [
+            "Noto Sans",
+            "Noto Sans CJK SC",
+            "Noto Sans Arabic",
+            "Noto Sans Hebrew",
+            "Noto Sans Devanagari",
+            "Noto Sans Thai",
+            "DejaVu Sans",
+            "Droid Sans Fallback",
+            "WenQuanYi Micro Hei",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+        Self {
+            families: HashMap::default(),
+            generics,
+            fallback,
+            decorations: HashMap::default(),
+            pending: Vec::new(),
+            failed: HashSet::default(),
+            default: None,
+            generation: 0,
+        }
+    }
+}
+
+impl FontRegistry {
+    /// Add a face to `family`, replacing any face with the same
+    /// weight and style.
+    pub fn register(
+        &mut self,
+        family: &str,
+        weight: FontWeight,
+        style: FontStyle,
+        font: Handle<Font>,
+    ) {
+        self.insert(
+            family,
+            FontFace {
+                weight,
+                style,
+                source: FaceSource::Asset(font),
+            },
+            true,
+        );
+        self.generation += 1;
+    }
+
+    fn insert(&mut self, family: &str, face: FontFace, replace: bool) {
+        let (_, faces) = self
+            .families
+            .entry(family.to_lowercase())
+            .or_insert_with(|| (family.to_string(), Vec::new()));
+        let same = |f: &FontFace| f.weight == face.weight && f.style == face.style;
+        if replace {
+            faces.retain(|f| !same(f));
+        } else if faces.iter().any(same) {
+            return;
+        }
+        faces.push(face);
+    }
+
+    /// Set the families tried, in order, for a generic family such as
+    /// [`FontFamily::SANS_SERIF`](piet::FontFamily::SANS_SERIF).
+    pub fn set_generic(&mut self, generic: &piet::FontFamily, families: Vec<String>) {
+        self.generics
+            .insert(generic.name().to_lowercase(), families);
+        self.generation += 1;
+    }
+
+    /// Set the families tried, in order, for characters missing
+    /// from a layout's font.
+    pub fn set_fallback(&mut self, families: Vec<String>) {
+        self.fallback = families;
+        self.generation += 1;
+    }
+
+    pub fn fallback(&self) -> &[String] {
+        &self.fallback
+    }
+
+    /// Changes whenever faces are registered, families are remapped
+    /// or font metrics are read, any of which can change layouts.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Registered families first, then generics.
+    fn key(&self, family: &str) -> Option<String> {
+        let family = family.to_lowercase();
+        if self.families.contains_key(&family) {
+            return Some(family);
+        }
+        self.generics
+            .get(&family)?
+            .iter()
+            .map(|f| f.to_lowercase())
+            .find(|f| self.families.contains_key(f))
+    }
+
+    pub fn contains(&self, family: &str) -> bool {
+        self.key(family).is_some()
+    }
+
+    /// The registered name of `family`, or of the family a generic
+    /// family maps to.
+    pub fn family_name(&self, family: &str) -> Option<&str> {
+        self.key(family)
+            .and_then(|key| self.families.get(&key))
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn families(&self) -> impl Iterator<Item = &str> {
+        self.families.values().map(|(name, _)| name.as_str())
+    }
+
+    pub fn faces(&self, family: &str) -> &[FontFace] {
+        self.key(family)
+            .and_then(|key| self.families.get(&key))
+            .map_or(&[], |(_, faces)| faces.as_slice())
+    }
+
+    /// The face in `family` nearest to `weight` and `style`.
+    pub fn resolve(&self, family: &str, weight: FontWeight, style: FontStyle) -> Option<&FontFace> {
+        let faces = self.faces(family);
+        nearest_face(faces, weight, style).map(|i| &faces[i])
+    }
+
+    /// The font for the face in `family` nearest to `weight` and
+    /// `style`, loading it into `fonts` if it came from a file.
+    /// Generic families with no installed family use the default
+    /// font.
+    pub fn load(
+        &mut self,
+        fonts: &mut Assets<Font>,
+        family: &str,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Result<Handle<Font>, piet::Error> {
+        if self.key(family).is_none() && self.generics.contains_key(&family.to_lowercase()) {
+            return self.default_font(fonts);
+        }
+        self.load_face(fonts, family, weight, style)
+    }
+
+    fn load_face(
+        &mut self,
+        fonts: &mut Assets<Font>,
+        family: &str,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Result<Handle<Font>, piet::Error> {
+        let key = self.key(family).ok_or(piet::Error::MissingFont)?;
+        let faces = &mut self.families.get_mut(&key).unwrap().1;
+        let i = nearest_face(faces, weight, style).ok_or(piet::Error::MissingFont)?;
+        let (path, index) = match &faces[i].source {
+            FaceSource::Asset(handle) => return Ok(handle.clone()),
+            FaceSource::File { path, index } => (path.clone(), *index),
+        };
+        match std::fs::read(path)
+            .map_err(|_| piet::Error::FontLoadingFailed)
+            .and_then(|data| {
+                let decorations = Decorations::parse(&data, index);
+                font_from_bytes(data, index).map(|font| (font, decorations))
+            }) {
+            Ok((font, decorations)) => {
+                let handle = fonts.add(font);
+                self.decorations
+                    .extend(decorations.map(|d| (handle.clone(), d)));
+                faces[i].source = FaceSource::Asset(handle.clone());
+                Ok(handle)
+            }
+            Err(e) => {
+                // Don't try again.
+                faces.remove(i);
+                Err(e)
+            }
+        }
+    }
+
+    /// The bundled [`DEFAULT_FONT`], added to `fonts` when first used.
+    pub fn default_font(&mut self, fonts: &mut Assets<Font>) -> Result<Handle<Font>, piet::Error> {
+        if let Some(handle) = &self.default {
+            return Ok(handle.clone());
+        }
+        let decorations = Decorations::parse(DEFAULT_FONT, 0);
+        let handle = fonts.add(font_from_bytes(DEFAULT_FONT.to_vec(), 0)?);
+        self.decorations
+            .extend(decorations.map(|d| (handle.clone(), d)));
+        self.default = Some(handle.clone());
+        Ok(handle)
+    }
+
+    /// A loaded font to lay out with in place of one that is still
+    /// loading or failed to load: the nearest face in the generic
+    /// sans-serif family, the first fallback family found, or else
+    /// the default font.
+    pub fn stand_in(
+        &mut self,
+        fonts: &mut Assets<Font>,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Result<Handle<Font>, piet::Error> {
+        let families: Vec<String> = std::iter::once(piet::FontFamily::SANS_SERIF.name().into())
+            .chain(self.fallback.iter().cloned())
+            .collect();
+        for family in &families {
+            match self.load_face(fonts, family, weight, style) {
+                Ok(handle) if fonts.get(&handle).is_some() => return Ok(handle),
+                _ => (),
+            }
+        }
+        self.default_font(fonts)
+    }
+
+    /// Report `font` with a [`FontEvent`] once it loads or fails to.
+    pub(crate) fn wait_for(&mut self, font: &Handle<Font>) {
+        if !self.pending.contains(font) {
+            self.pending.push(font.clone());
+        }
+    }
+
+    /// Whether any layout is waiting on a font to load.
+    pub fn is_loading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Whether `font` failed to load. Layouts use a stand-in for it.
+    pub fn has_failed(&self, font: &Handle<Font>) -> bool {
+        self.failed.contains(font)
+    }
+
+    /// Split `text` into runs of `font` and fallback fonts. Each
+    /// character uses the first font in the chain that has a glyph
+    /// for it; whitespace stays with the preceding run. Ranges are
+    /// byte offsets into `text`.
+    pub fn fallback_runs(
+        &mut self,
+        fonts: &mut Assets<Font>,
+        text: &str,
+        font: Handle<Font>,
+        weight: FontWeight,
+        style: FontStyle,
+    ) -> Vec<(Range<usize>, Handle<Font>)> {
+        // If the font is still loading, assume it covers everything.
+        if fonts.get(&font).is_none() || text.is_empty() {
+            return vec![(0..text.len(), font)];
+        }
+
+        // Fallback fonts are loaded as needed. None if unavailable.
+        let mut chain: Vec<Option<Handle<Font>>> = Vec::new();
+        let mut runs: Vec<(Range<usize>, Handle<Font>)> = Vec::new();
+        for (i, c) in text.char_indices() {
+            let handle = if c.is_whitespace() || c.is_control() {
+                runs.last().map_or_else(|| font.clone(), |(_, h)| h.clone())
+            } else if has_glyph(fonts, &font, c) {
+                font.clone()
+            } else {
+                (0..self.fallback.len())
+                    .find_map(|j| {
+                        if j == chain.len() {
+                            let family = self.fallback[j].clone();
+                            chain.push(self.load(fonts, &family, weight, style).ok());
+                        }
+                        chain[j].clone().filter(|h| has_glyph(fonts, h, c))
+                    })
+                    // Draws the missing glyph box.
+                    .unwrap_or_else(|| font.clone())
+            };
+
+            let end = i + c.len_utf8();
+            match runs.last_mut() {
+                Some((range, h)) if *h == handle => range.end = end,
+                _ => runs.push((i..end, handle)),
+            }
+        }
+        runs
+    }
+
+    /// Parse font `data`, add it to `fonts`, and register it under
+    /// the family name found in the font. Returns the family name.
+    pub fn add_font(
+        &mut self,
+        fonts: &mut Assets<Font>,
+        data: Vec<u8>,
+    ) -> Result<String, piet::Error> {
+        let info = FaceInfo::parse(&data, 0).ok_or(piet::Error::FontLoadingFailed)?;
+        let decorations = Decorations::parse(&data, 0);
+        let handle = fonts.add(font_from_bytes(data, 0)?);
+        self.decorations
+            .extend(decorations.map(|d| (handle.clone(), d)));
+        self.register(&info.family, info.weight, info.style, handle);
+        Ok(info.family)
+    }
+
+    /// Underline and strikethrough metrics for `font`. Fonts that
+    /// weren't loaded from data or files get the defaults.
+    pub fn decorations(&self, font: &Handle<Font>) -> Decorations {
+        self.decorations.get(font).copied().unwrap_or_default()
+    }
+
+    // Read the decorations of a font the asset server loaded from a
+    // file. Only its metric tables are read. Fonts from other asset
+    // sources keep the defaults.
+    fn read_decorations(&mut self, asset_server: &AssetServer, font: &Handle<Font>) {
+        let path = asset_server.get_handle_path(font).and_then(|path| {
+            let io = asset_server.asset_io().downcast_ref::<FileAssetIo>()?;
+            Some(io.root_path().join(path.path()))
+        });
+        let decorations = path
+            .and_then(|path| File::open(path).ok())
+            .and_then(|mut file| face_tables(&mut file, 0))
+            .and_then(|tables| Decorations::parse(&tables, 0));
+        if let Some(decorations) = decorations {
+            self.decorations.insert(font.clone_weak(), decorations);
+            self.generation += 1;
+        }
+    }
+
+    /// Find the faces in the system font directories. Only the tables
+    /// that name each face are read; faces are loaded when first used,
+    /// and don't replace faces that are already registered. Returns
+    /// the number of faces found.
+    pub fn add_system_fonts(&mut self) -> usize {
+        let mut count = 0;
+        for dir in system_font_dirs() {
+            let mut files = Vec::new();
+            font_files(&dir, 0, &mut files);
+            for path in files {
+                let mut file = match File::open(&path) {
+                    Ok(file) => file,
+                    Err(_) => continue,
+                };
+                let faces = read_at(&mut file, 0, 12)
+                    .and_then(|header| ttf_parser::fonts_in_collection(&header))
+                    .unwrap_or(1);
+                for index in 0..faces {
+                    let info = face_tables(&mut file, index)
+                        .and_then(|tables| FaceInfo::parse(&tables, 0));
+                    if let Some(info) = info {
+                        self.insert(
+                            &info.family,
+                            FontFace {
+                                weight: info.weight,
+                                style: info.style,
+                                source: FaceSource::File {
+                                    path: path.clone(),
+                                    index,
+                                },
+                            },
+                            false,
+                        );
+                        count += 1;
+                    }
+                }
+            }
+        }
+        if count > 0 {
+            self.generation += 1;
+        }
+        count
+    }
+}
+
+fn has_glyph(fonts: &Assets<Font>, font: &Handle<Font>, c: char) -> bool {
+    fonts
+        .get(font)
+        .map_or(false, |f| ab_glyph::Font::glyph_id(&f.font, c).0 != 0)
+}
+
+fn font_from_bytes(data: Vec<u8>, index: u32) -> Result<Font, piet::Error> {
+    FontVec::try_from_vec_and_index(data, index)
+        .map(|font| Font {
+            font: FontArc::new(font),
+        })
+        .map_err(|_| piet::Error::FontLoadingFailed)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn system_font_dirs() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|h| h.join(".local/share")));
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .unwrap_or_else(|_| "/usr/local/share:/usr/share".to_string());
+
+    // User fonts first so they win.
+    let mut dirs: Vec<PathBuf> = data_home
+        .map(|d| d.join("fonts"))
+        .into_iter()
+        .chain(home.map(|h| h.join(".fonts")))
+        .chain(
+            data_dirs
+                .split(':')
+                .filter(|d| !d.is_empty())
+                .map(|d| Path::new(d).join("fonts")),
+        )
+        .collect();
+    dirs.dedup();
+    dirs
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn system_font_dirs() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+// `len` bytes of `file` from `offset`.
+fn read_at(file: &mut File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut buf).ok()?;
+    Some(buf)
+}
+
+// The tables FaceInfo and Decorations read, sorted by tag. ttf-parser
+// needs head, hhea and maxp to open a face.
+const FACE_TABLES: [&[u8; 4]; 6] = [b"OS/2", b"head", b"hhea", b"maxp", b"name", b"post"];
+
+// Copy the face at `index` in `file`, keeping only FACE_TABLES, so
+// large fonts and collections aren't read in full.
+fn face_tables(file: &mut File, index: u32) -> Option<Vec<u8>> {
+    let header = read_at(file, 0, 12)?;
+    let be32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+    let start = if &header[..4] == b"ttcf" {
+        be32(&read_at(file, 12 + 4 * index as u64, 4)?) as u64
+    } else {
+        0
+    };
+    let dir = read_at(file, start, 12)?;
+    let num_tables = u16::from_be_bytes([dir[4], dir[5]]) as usize;
+    let records = read_at(file, start + 12, num_tables * 16)?;
+    let mut tables = Vec::new();
+    for record in records.chunks_exact(16) {
+        if let Some(tag) = FACE_TABLES.iter().find(|t| record[..4] == t[..]) {
+            let data = read_at(
+                file,
+                be32(&record[8..]) as u64,
+                be32(&record[12..]) as usize,
+            )?;
+            tables.push((*tag, data));
+        }
+    }
+
+    // A font of just those tables. Checksums and search hints aren't
+    // checked.
+    let mut font = dir[..4].to_vec();
+    font.extend((tables.len() as u16).to_be_bytes());
+    font.extend([0; 6]);
+    let mut offset = 12 + 16 * tables.len();
+    for (tag, data) in &tables {
+        font.extend(*tag);
+        font.extend(0u32.to_be_bytes());
+        font.extend((offset as u32).to_be_bytes());
+        font.extend((data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+    for (_, data) in tables {
+        font.extend(data);
+    }
+    Some(font)
+}
+
+// Recursive, with a depth limit in case of symlink loops.
+fn font_files(dir: &Path, depth: usize, files: &mut Vec<PathBuf>) {
+    if depth > 8 {
+        return;
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for path in entries.filter_map(|e| e.ok().map(|e| e.path())) {
+        if path.is_dir() {
+            font_files(&path, depth + 1, files);
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map_or(false, |e| {
+                ["ttf", "otf", "ttc", "otc"].contains(&e.to_lowercase().as_str())
+            })
+        {
+            files.push(path);
+        }
+    }
+}
+
+/// Sent when a font that layouts were built without has loaded or
+/// failed to load. Layouts built while it was loading used a stand-in
+/// font and should be built again on `Loaded`.
+#[derive(Clone, Debug)]
+pub enum FontEvent {
+    Loaded(Handle<Font>),
+    Failed(Handle<Font>),
+}
+
+/// Checks on the fonts layouts are waiting for and sends a
+/// [`FontEvent`] for each one that has loaded or failed to load.
+pub fn watch_fonts(
+    fonts: Res<Assets<Font>>,
+    asset_server: Res<AssetServer>,
+    mut registry: ResMut<FontRegistry>,
+    mut asset_events: EventReader<AssetEvent<Font>>,
+    mut events: EventWriter<FontEvent>,
+) {
+    let registry = &mut *registry;
+    for event in asset_events.iter() {
+        match event {
+            // Fonts added from data have theirs already.
+            AssetEvent::Created { handle } if !registry.decorations.contains_key(handle) => {
+                registry.read_decorations(&asset_server, handle)
+            }
+            AssetEvent::Modified { handle } => {
+                registry.read_decorations(&asset_server, handle);
+                registry.generation += 1;
+            }
+            AssetEvent::Removed { handle } => {
+                registry.decorations.remove(handle);
+                registry.generation += 1;
+            }
+            _ => (),
+        }
+    }
+
+    if !registry.is_loading() {
+        return;
+    }
+    for font in std::mem::take(&mut registry.pending) {
+        if fonts.get(&font).is_some() {
+            events.send(FontEvent::Loaded(font));
+        } else if asset_server.get_load_state(&font) == LoadState::Failed {
+            warn!(
+                "failed to load font {:?}",
+                asset_server.get_handle_path(&font)
+            );
+            registry.failed.insert(font.clone());
+            events.send(FontEvent::Failed(font));
+        } else {
+            registry.pending.push(font);
+        }
+    }
+}
+
+/// The names and properties of a font face.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaceInfo {
+    pub family: String,
+    pub weight: FontWeight,
+    pub style: FontStyle,
+}
+
+impl FaceInfo {
+    /// Read the face at `index` in `data`.
+    pub fn parse(data: &[u8], index: u32) -> Option<Self> {
+        let face = ttf_parser::Face::from_slice(data, index).ok()?;
+        // The typographic family groups more faces than the legacy
+        // family (which is limited to four styles). Only weight and
+        // style are matched, so condensed and expanded faces keep
+        // their legacy family, e.g. "DejaVu Sans Condensed".
+        let (first, second) = if face.width() == ttf_parser::Width::Normal {
+            (name_id::TYPOGRAPHIC_FAMILY, name_id::FAMILY)
+        } else {
+            (name_id::FAMILY, name_id::TYPOGRAPHIC_FAMILY)
+        };
+        let family = face_name(&face, first).or_else(|| face_name(&face, second))?;
+        Some(Self {
+            family,
+            weight: FontWeight::new(face.weight().to_number()),
+            style: if face.is_italic() || face.is_oblique() {
+                FontStyle::Italic
+            } else {
+                FontStyle::Regular
+            },
+        })
+    }
+}
+
+/// Where to draw underlines and strikethroughs, in ems. Positions
+/// are the top of the line, above the baseline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decorations {
+    pub underline_position: f32,
+    pub underline_thickness: f32,
+    pub strikethrough_position: f32,
+    pub strikethrough_thickness: f32,
+}
+
+impl Default for Decorations {
+    fn default() -> Self {
+        Self {
+            underline_position: -0.1,
+            underline_thickness: 0.07,
+            strikethrough_position: 0.3,
+            strikethrough_thickness: 0.07,
+        }
+    }
+}
+
+impl Decorations {
+    /// Read the post and OS/2 tables of the face at `index` in
+    /// `data`. Missing metrics are the defaults.
+    pub fn parse(data: &[u8], index: u32) -> Option<Self> {
+        let face = ttf_parser::Face::from_slice(data, index).ok()?;
+        let em = face.units_per_em() as f32;
+        let default = Self::default();
+        let metrics = |m: Option<ttf_parser::LineMetrics>, position, thickness| match m {
+            Some(m) if m.thickness > 0 => (m.position as f32 / em, m.thickness as f32 / em),
+            _ => (position, thickness),
+        };
+        let (underline_position, underline_thickness) = metrics(
+            face.underline_metrics(),
+            default.underline_position,
+            default.underline_thickness,
+        );
+        let (strikethrough_position, strikethrough_thickness) = metrics(
+            face.strikeout_metrics(),
+            default.strikethrough_position,
+            default.strikethrough_thickness,
+        );
+        Some(Self {
+            underline_position,
+            underline_thickness,
+            strikethrough_position,
+            strikethrough_thickness,
+        })
+    }
+}
+
+// Prefers US English, otherwise the first name that decodes.
+fn face_name(face: &ttf_parser::Face, id: u16) -> Option<String> {
+    const EN_US: u16 = 0x0409;
+    let mut found = None;
+    for name in face.names().into_iter().filter(|n| n.name_id == id) {
+        if let Some(s) = name.to_string() {
+            if name.language_id == EN_US {
+                return Some(s);
+            }
+            found.get_or_insert(s);
+        }
+    }
+    found
+}
+
+// CSS font matching: the style must match if it can, then weights
+// are searched in the direction of the desired weight. Returns the
+// index of the face.
+fn nearest_face(faces: &[FontFace], weight: FontWeight, style: FontStyle) -> Option<usize> {
+    let any_styled = faces.iter().any(|f| f.style == style);
+    faces
+        .iter()
+        .enumerate()
+        .filter(|(_, f)| !any_styled || f.style == style)
+        .min_by_key(|(_, f)| weight_distance(weight.to_raw(), f.weight.to_raw()))
+        .map(|(i, _)| i)
+}
+
+// Lower is nearer. Faces in the preferred direction come first,
+// ordered by distance, then the rest.
+fn weight_distance(desired: u16, weight: u16) -> (bool, u16) {
+    let (desired, weight) = (desired as i32, weight as i32);
+    let away = (desired - weight).unsigned_abs() as u16;
+    if (400..=500).contains(&desired) {
+        // Up to 500 first, then lighter, then heavier.
+        if weight >= desired && weight <= 500 {
+            (false, away)
+        } else if weight < desired {
+            (true, away)
+        } else {
+            (true, away + 1000)
+        }
+    } else if desired < 400 {
+        (weight > desired, away)
+    } else {
+        (weight < desired, away)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::{
+        asset::AddAsset,
+        prelude::{App, MinimalPlugins},
+    };
+
+    // Arrows and box drawing that DEFAULT_FONT lacks, under the SIL
+    // Open Font License (`fonts/Inconsolata-OFL.txt`).
+    const INCONSOLATA: &[u8] = include_bytes!("../fonts/Inconsolata-variable.ttf");
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(bevy::asset::AssetPlugin)
+            .add_asset::<Font>();
+        app
+    }
+
+    fn face(weight: u16, style: FontStyle) -> FontFace {
+        FontFace {
+            weight: FontWeight::new(weight),
+            style,
+            source: FaceSource::Asset(Handle::default()),
+        }
+    }
+
+    fn resolve(faces: &[FontFace], weight: u16, style: FontStyle) -> (u16, FontStyle) {
+        let f = &faces[nearest_face(faces, FontWeight::new(weight), style).unwrap()];
+        (f.weight.to_raw(), f.style)
+    }
+
+    #[test]
+    fn nearest() {
+        let faces = [
+            face(300, FontStyle::Regular),
+            face(400, FontStyle::Regular),
+            face(700, FontStyle::Regular),
+            face(400, FontStyle::Italic),
+        ];
+        assert_eq!(
+            resolve(&faces, 400, FontStyle::Regular),
+            (400, FontStyle::Regular)
+        );
+        assert_eq!(
+            resolve(&faces, 500, FontStyle::Regular),
+            (400, FontStyle::Regular)
+        );
+        assert_eq!(
+            resolve(&faces, 600, FontStyle::Regular),
+            (700, FontStyle::Regular)
+        );
+        assert_eq!(
+            resolve(&faces, 900, FontStyle::Regular),
+            (700, FontStyle::Regular)
+        );
+        assert_eq!(
+            resolve(&faces, 200, FontStyle::Regular),
+            (300, FontStyle::Regular)
+        );
+        assert_eq!(
+            resolve(&faces, 350, FontStyle::Regular),
+            (300, FontStyle::Regular)
+        );
+        // Style wins over weight.
+        assert_eq!(
+            resolve(&faces, 700, FontStyle::Italic),
+            (400, FontStyle::Italic)
+        );
+        assert_eq!(
+            resolve(&faces[..3], 400, FontStyle::Italic),
+            (400, FontStyle::Regular)
+        );
+        assert!(nearest_face(&[], FontWeight::REGULAR, FontStyle::Regular).is_none());
+    }
+
+    #[test]
+    fn generic() {
+        let mut registry = FontRegistry::default();
+        assert!(!registry.contains("sans-serif"));
+        registry.register(
+            "Noto Sans",
+            FontWeight::REGULAR,
+            FontStyle::Regular,
+            Handle::default(),
+        );
+        assert_eq!(registry.family_name("sans-serif"), Some("Noto Sans"));
+        assert_eq!(registry.family_name("noto sans"), Some("Noto Sans"));
+        assert_eq!(registry.family_name("serif"), None);
+    }
+
+    #[test]
+    fn generic_without_families() {
+        let mut app = app();
+        let mut fonts = app.world.resource_mut::<Assets<Font>>();
+        let mut registry = FontRegistry::default();
+        let default = registry.default_font(&mut fonts).unwrap();
+        for generic in [
+            piet::FontFamily::SANS_SERIF,
+            piet::FontFamily::SERIF,
+            piet::FontFamily::MONOSPACE,
+            piet::FontFamily::SYSTEM_UI,
+        ] {
+            let font = registry.load(
+                &mut fonts,
+                generic.name(),
+                FontWeight::BOLD,
+                FontStyle::Italic,
+            );
+            assert_eq!(font.ok(), Some(default.clone()));
+        }
+        let stand_in = registry.stand_in(&mut fonts, FontWeight::REGULAR, FontStyle::Regular);
+        assert_eq!(stand_in.ok(), Some(default));
+        assert!(matches!(
+            registry.load(
+                &mut fonts,
+                "Vollkorn",
+                FontWeight::REGULAR,
+                FontStyle::Regular
+            ),
+            Err(piet::Error::MissingFont)
+        ));
+    }
+
+    #[test]
+    fn default_font() {
+        let info = FaceInfo::parse(DEFAULT_FONT, 0).unwrap();
+        assert_eq!(info.family, "Anaheim");
+        assert_eq!(info.weight, FontWeight::REGULAR);
+        assert!(Decorations::parse(DEFAULT_FONT, 0).is_some());
+    }
+
+    #[test]
+    fn add_font() {
+        let mut app = app();
+        let mut fonts = app.world.resource_mut::<Assets<Font>>();
+        let mut registry = FontRegistry::default();
+        let generation = registry.generation();
+
+        let family = registry
+            .add_font(&mut fonts, DEFAULT_FONT.to_vec())
+            .unwrap();
+        assert_eq!(family, "Anaheim");
+        // What `font_family` looks up.
+        assert_eq!(registry.family_name("anaheim"), Some("Anaheim"));
+        assert!(registry.generation() > generation);
+
+        let handle = registry
+            .load(&mut fonts, "anaheim", FontWeight::BOLD, FontStyle::Regular)
+            .unwrap();
+        assert!(fonts.get(&handle).is_some());
+        assert_eq!(
+            Some(registry.decorations(&handle)),
+            Decorations::parse(DEFAULT_FONT, 0)
+        );
+
+        assert!(registry.add_font(&mut fonts, vec![0; 64]).is_err());
+    }
+
+    #[test]
+    fn fallback_runs() {
+        let mut app = app();
+        let mut fonts = app.world.resource_mut::<Assets<Font>>();
+        let mut registry = FontRegistry::default();
+        let font = registry.default_font(&mut fonts).unwrap();
+        let family = registry.add_font(&mut fonts, INCONSOLATA.to_vec()).unwrap();
+        // Unavailable families are skipped.
+        registry.set_fallback(vec!["Missing".into(), family.clone()]);
+        let fallback = registry
+            .load(&mut fonts, &family, FontWeight::REGULAR, FontStyle::Regular)
+            .unwrap();
+        let mut runs = |text: &str| {
+            registry.fallback_runs(
+                &mut fonts,
+                text,
+                font.clone(),
+                FontWeight::REGULAR,
+                FontStyle::Regular,
+            )
+        };
+
+        // The space after the arrow stays with it; no font has the
+        // star, so it draws the missing glyph from the first.
+        assert_eq!(
+            runs("a \u{2192} b \u{2605} c"),
+            vec![
+                (0..2, font.clone()),
+                (2..6, fallback.clone()),
+                (6..13, font.clone())
+            ]
+        );
+        // Leading whitespace uses the first font.
+        assert_eq!(
+            runs(" \u{2500}\u{2500}"),
+            vec![(0..1, font.clone()), (1..7, fallback)]
+        );
+        assert_eq!(runs(""), vec![(0..0, font.clone())]);
+
+        // A font that is still loading is assumed to cover everything.
+        let loading = Handle::<Font>::default();
+        let runs = registry.fallback_runs(
+            &mut fonts,
+            "a \u{2192}",
+            loading.clone(),
+            FontWeight::REGULAR,
+            FontStyle::Regular,
+        );
+        assert_eq!(runs, vec![(0..5, loading)]);
+    }
+
+    #[test]
+    fn face_tables_only() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fonts/Anaheim-Regular.ttf");
+        let tables = face_tables(&mut File::open(path).unwrap(), 0).unwrap();
+        assert!(tables.len() < DEFAULT_FONT.len() / 2);
+        assert_eq!(
+            FaceInfo::parse(&tables, 0),
+            FaceInfo::parse(DEFAULT_FONT, 0)
+        );
+        assert_eq!(
+            Decorations::parse(&tables, 0),
+            Decorations::parse(DEFAULT_FONT, 0)
+        );
+    }
+}
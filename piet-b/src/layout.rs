@@ -0,0 +1,912 @@
+//! Lines and line placement. glyph_brush_layout breaks lines and lays
+//! them out left to right in logical order; here each line is
+//! reordered for bidi text, then aligned and justified.
+
+use piet::{LineMetric, TextAlignment};
+use std::ops::Range;
+use unicode_bidi::BidiInfo;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A grapheme cluster's place in a layout, in dp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cluster {
+    pub byte_index: usize,
+    /// Length in bytes.
+    pub len: usize,
+    /// The left edge.
+    pub x: f64,
+    pub advance: f64,
+    pub rtl: bool,
+    pub line: usize,
+}
+
+impl Cluster {
+    /// The caret position before this cluster.
+    pub fn leading_edge(&self) -> f64 {
+        if self.rtl {
+            self.x + self.advance
+        } else {
+            self.x
+        }
+    }
+
+    /// The caret position after this cluster.
+    pub fn trailing_edge(&self) -> f64 {
+        if self.rtl {
+            self.x
+        } else {
+            self.x + self.advance
+        }
+    }
+}
+
+/// Which cluster a caret at an offset sits against: the one after it
+/// or the one before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Affinity {
+    /// The leading edge of the cluster after the offset.
+    Leading,
+    /// The trailing edge of the cluster before the offset.
+    Trailing,
+}
+
+impl Default for Affinity {
+    fn default() -> Self {
+        Affinity::Leading
+    }
+}
+
+/// Where tabs stop, measured from the start of each line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TabStops {
+    /// Every this many widths of a space.
+    Spaces(u32),
+    /// At these positions, in dp, in ascending order. Tabs past the
+    /// last stop are as wide as a space.
+    Positions(Vec<f64>),
+}
+
+impl Default for TabStops {
+    fn default() -> Self {
+        TabStops::Spaces(8)
+    }
+}
+
+impl TabStops {
+    // The first stop after `x`.
+    fn next(&self, x: f64, space: f64) -> f64 {
+        match self {
+            TabStops::Spaces(n) => {
+                let interval = *n as f64 * space;
+                if interval > 0.0 {
+                    ((x / interval + 1e-6).floor() + 1.0) * interval
+                } else {
+                    x
+                }
+            }
+            TabStops::Positions(stops) => stops
+                .iter()
+                .copied()
+                .find(|&stop| stop > x + 1e-6)
+                .unwrap_or(x + space),
+        }
+    }
+}
+
+pub(crate) struct Placement {
+    /// In logical order.
+    pub clusters: Vec<Cluster>,
+    pub width: f64,
+    /// Where the caret goes at the start of each line.
+    pub line_starts: Vec<f64>,
+    /// The width of the widest line with its trailing whitespace.
+    pub trailing_whitespace_width: f64,
+}
+
+/// The index of the cluster containing byte `idx`.
+pub(crate) fn cluster_index(clusters: &[Cluster], idx: usize) -> Option<usize> {
+    let i = clusters
+        .partition_point(|c| c.byte_index <= idx)
+        .checked_sub(1)?;
+    (idx < clusters[i].byte_index + clusters[i].len).then(|| i)
+}
+
+/// The horizontal spans covered by the clusters starting in
+/// `range`: the line, left and right, in visual order.
+pub(crate) fn spans(clusters: &[Cluster], range: Range<usize>) -> Vec<(usize, f64, f64)> {
+    let mut covered: Vec<_> = clusters
+        .iter()
+        .filter(|c| range.contains(&c.byte_index))
+        .collect();
+    covered.sort_by(|a, b| {
+        (a.line, a.x)
+            .partial_cmp(&(b.line, b.x))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let mut spans: Vec<(usize, f64, f64)> = Vec::new();
+    for c in covered {
+        match spans.last_mut() {
+            Some((line, _, right)) if *line == c.line && (c.x - *right).abs() < 1e-6 => {
+                *right = c.x + c.advance
+            }
+            _ => spans.push((c.line, c.x, c.x + c.advance)),
+        }
+    }
+    spans
+}
+
+/// Where the clusters in `range` that fit in `width`, counting from
+/// its start, end.
+pub(crate) fn fit_from_start(clusters: &[Cluster], range: Range<usize>, width: f64) -> usize {
+    let mut pen = 0.0;
+    for c in clusters.iter().filter(|c| range.contains(&c.byte_index)) {
+        pen += c.advance;
+        if pen > width + 1e-6 {
+            return c.byte_index;
+        }
+    }
+    range.end
+}
+
+/// Where the clusters in `range` that fit in `width`, counting back
+/// from its end, start.
+pub(crate) fn fit_from_end(clusters: &[Cluster], range: Range<usize>, width: f64) -> usize {
+    let mut pen = 0.0;
+    let mut start = range.end;
+    for c in clusters
+        .iter()
+        .rev()
+        .filter(|c| range.contains(&c.byte_index))
+    {
+        pen += c.advance;
+        if pen > width + 1e-6 {
+            break;
+        }
+        start = c.byte_index;
+    }
+    start
+}
+
+/// Characters the line breaker always breaks after.
+pub(crate) fn is_newline(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{000B}' | '\u{000C}' | '\u{0085}' | '\u{2028}' | '\u{2029}'
+    )
+}
+
+/// Split `text` into lines where glyph_brush_layout broke it.
+/// `wrapped` has the byte index and y of each glyph it placed, in
+/// order. Lines end after each hard break, and wherever a glyph moves
+/// down without one. A trailing hard break ends with an empty line.
+pub(crate) fn line_ranges(
+    text: &str,
+    wrapped: impl IntoIterator<Item = (usize, f32)>,
+) -> Vec<Range<usize>> {
+    let mut starts = vec![0];
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        // CR LF breaks once, after the LF.
+        let crlf = c == '\r' && matches!(chars.peek(), Some((_, '\n')));
+        if is_newline(c) && !crlf {
+            starts.push(i + c.len_utf8());
+        }
+    }
+
+    let mut soft = Vec::new();
+    let mut prev: Option<(usize, f32)> = None;
+    for (i, y) in wrapped {
+        if let Some((p, py)) = prev {
+            let hard = starts.get(starts.partition_point(|&s| s <= p));
+            if y != py && hard.map_or(true, |&s| s > i) {
+                soft.push(i);
+            }
+        }
+        prev = Some((i, y));
+    }
+    starts.extend(soft);
+    starts.sort_unstable();
+    starts.dedup();
+
+    let ends = starts.iter().skip(1).copied().chain([text.len()]);
+    starts.iter().zip(ends).map(|(&s, e)| s..e).collect()
+}
+
+/// The metrics of each line. `metrics` gives the ascent, descent
+/// (negative) and line gap of the font at a byte offset. As in
+/// glyph_brush_layout, the tallest font on a line sets its metrics.
+pub(crate) fn line_metrics(
+    text: &str,
+    lines: &[Range<usize>],
+    metrics: impl Fn(usize) -> (f64, f64, f64),
+    line_height: impl Fn(usize) -> Option<f64>,
+) -> Vec<LineMetric> {
+    let mut y_offset = 0.0;
+    lines
+        .iter()
+        .map(|range| {
+            let line = &text[range.clone()];
+            // Empty lines use the font where they are.
+            let mut at: Vec<usize> = line.char_indices().map(|(i, _)| range.start + i).collect();
+            if at.is_empty() {
+                at.push(range.start);
+            }
+            let (baseline, height, advance) = if at.iter().any(|&i| line_height(i).is_some()) {
+                // Split the difference from each font's height above
+                // and below it, like CSS.
+                let (above, below) = at
+                    .iter()
+                    .map(|&i| {
+                        let (ascent, descent, line_gap) = metrics(i);
+                        let height = line_height(i).unwrap_or(ascent - descent + line_gap);
+                        let half = (height - (ascent - descent)) * 0.5;
+                        (ascent + half, half - descent)
+                    })
+                    .reduce(|a, b| (a.0.max(b.0), a.1.max(b.1)))
+                    .unwrap_or_default();
+                let height = (above + below).max(0.0);
+                (above, height, height)
+            } else {
+                let (ascent, descent, line_gap) = at
+                    .iter()
+                    .map(|&i| metrics(i))
+                    .reduce(|a, b| if b.0 - b.1 > a.0 - a.1 { b } else { a })
+                    .unwrap_or_default();
+                (ascent, ascent - descent, ascent - descent + line_gap)
+            };
+            let metric = LineMetric {
+                start_offset: range.start,
+                end_offset: range.end,
+                // Includes the line break.
+                trailing_whitespace: line.len() - line.trim_end().len(),
+                baseline,
+                height,
+                y_offset,
+            };
+            y_offset += advance;
+            metric
+        })
+        .collect()
+}
+
+/// How far to move the glyphs of each cluster from where
+/// glyph_brush_layout put them, right and up. `origins` has the left
+/// edge and baseline of each character it placed, by byte offset, with
+/// baselines as heights above the bottom of a layout `height` high.
+pub(crate) fn cluster_shifts(
+    clusters: &[Cluster],
+    metrics: &[LineMetric],
+    height: f64,
+    origins: &[Option<(f64, f64)>],
+) -> Vec<(f64, f64)> {
+    clusters
+        .iter()
+        .map(|c| match (origins.get(c.byte_index), metrics.get(c.line)) {
+            (Some(&Some((x, baseline))), Some(l)) => {
+                (c.x - x, height - (l.y_offset + l.baseline) - baseline)
+            }
+            _ => (0.0, 0.0),
+        })
+        .collect()
+}
+
+// The grapheme clusters in `range`, as line `n`, from left to right
+// in logical order. Characters are spaced out and tabs widened to the
+// next stop.
+fn spaced_clusters(
+    text: &str,
+    range: Range<usize>,
+    n: usize,
+    advances: &[f64],
+    tab_stops: &TabStops,
+    letter_spacing: &impl Fn(usize) -> f64,
+) -> Vec<Cluster> {
+    let mut pen = 0.0;
+    text[range.clone()]
+        .grapheme_indices(true)
+        .map(|(i, g)| {
+            let i = range.start + i;
+            // Control characters have no advance. Tabs are laid out
+            // as spaces.
+            let mut advance = g
+                .char_indices()
+                .filter(|&(_, c)| (c == '\t' || !c.is_control()) && !is_newline(c))
+                .map(|(j, _)| advances[i + j])
+                .sum();
+            if g.starts_with('\t') {
+                advance = tab_stops.next(pen, advance) - pen;
+            } else if advance != 0.0 {
+                advance += letter_spacing(i);
+            }
+            let c = Cluster {
+                byte_index: i,
+                len: g.len(),
+                x: pen,
+                advance,
+                rtl: false,
+                line: n,
+            };
+            pen += advance;
+            c
+        })
+        .collect()
+}
+
+/// Break `lines` again where spacing and tabs made them wider than
+/// `max_width`: after the last whitespace that fits, or else before
+/// the first cluster that doesn't. A line keeps at least one cluster.
+pub(crate) fn wrap_lines(
+    text: &str,
+    lines: &[Range<usize>],
+    advances: &[f64],
+    max_width: f64,
+    tab_stops: &TabStops,
+    letter_spacing: impl Fn(usize) -> f64,
+) -> Vec<Range<usize>> {
+    let is_space = |c: &Cluster| text[c.byte_index..].starts_with(char::is_whitespace);
+    let mut wrapped = Vec::with_capacity(lines.len());
+    for range in lines {
+        let mut start = range.start;
+        loop {
+            let line = spaced_clusters(
+                text,
+                start..range.end,
+                0,
+                advances,
+                tab_stops,
+                &letter_spacing,
+            );
+            let mut opportunity = None;
+            let mut end = None;
+            for (k, c) in line.iter().enumerate() {
+                if is_space(c) {
+                    continue;
+                }
+                if k > 0 && is_space(&line[k - 1]) && line[..k].iter().any(|c| !is_space(c)) {
+                    opportunity = Some(c.byte_index);
+                }
+                if k > 0 && c.x + c.advance > max_width {
+                    end = Some(opportunity.unwrap_or(c.byte_index));
+                    break;
+                }
+            }
+            match end {
+                Some(end) => {
+                    wrapped.push(start..end);
+                    start = end;
+                }
+                None => {
+                    wrapped.push(start..range.end);
+                    break;
+                }
+            }
+        }
+    }
+    wrapped
+}
+
+/// Place the characters in `lines`, which are contiguous byte ranges
+/// of `text`. `advances` has the advance of each character at its
+/// byte offset.
+pub(crate) fn place_lines(
+    text: &str,
+    lines: &[Range<usize>],
+    advances: &[f64],
+    alignment: TextAlignment,
+    max_width: f64,
+    tab_stops: &TabStops,
+    letter_spacing: impl Fn(usize) -> f64,
+) -> Placement {
+    let bidi = BidiInfo::new(text, None);
+    let justify = alignment == TextAlignment::Justified && max_width < f64::MAX;
+
+    let mut clusters: Vec<Cluster> = Vec::new();
+    // Cluster range, paragraph direction, content left and width.
+    let mut placed = Vec::new();
+    let mut trailing_whitespace_width: f64 = 0.0;
+
+    for (n, range) in lines.iter().enumerate() {
+        let first = clusters.len();
+        let para = bidi
+            .paragraphs
+            .iter()
+            .find(|p| p.range.contains(&range.start))
+            .or_else(|| bidi.paragraphs.last());
+        let para_rtl = para.map_or(false, |p| p.level.is_rtl());
+
+        clusters.extend(spaced_clusters(
+            text,
+            range.clone(),
+            n,
+            advances,
+            tab_stops,
+            &letter_spacing,
+        ));
+        let line = &mut clusters[first..];
+
+        // Trailing whitespace is not content.
+        let is_space = |c: &Cluster| text[c.byte_index..].starts_with(char::is_whitespace);
+        let content_end = line.iter().rposition(|c| !is_space(c)).map_or(0, |i| i + 1);
+        let content_start = line.iter().position(|c| !is_space(c)).unwrap_or(0);
+
+        // Visual order.
+        let mut order: Vec<usize> = Vec::with_capacity(line.len());
+        match para {
+            Some(para) if !range.is_empty() => {
+                let (levels, runs) = bidi.visual_runs(para, range.clone());
+                let index = |b: usize| line.partition_point(|c| c.byte_index < b);
+                let runs: Vec<_> = runs
+                    .into_iter()
+                    .map(|run| (index(run.start), index(run.end), levels[run.start].is_rtl()))
+                    .collect();
+                for (a, b, rtl) in runs {
+                    for c in &mut line[a..b] {
+                        c.rtl = rtl;
+                    }
+                    if rtl {
+                        order.extend((a..b).rev());
+                    } else {
+                        order.extend(a..b);
+                    }
+                }
+            }
+            _ => order.extend(0..line.len()),
+        }
+
+        // Spread the space left between words, except on the last line
+        // of a paragraph.
+        let content_width: f64 = line[..content_end].iter().map(|c| c.advance).sum();
+        let last_in_paragraph = n + 1 == lines.len()
+            || text[range.clone()]
+                .chars()
+                .next_back()
+                .map_or(true, is_newline);
+        let gap: Vec<bool> = (0..line.len())
+            .map(|i| i > content_start && i < content_end && is_space(&line[i]))
+            .collect();
+        let gaps = gap.iter().filter(|&&g| g).count();
+        let extra = if justify && !last_in_paragraph && gaps > 0 && content_width < max_width {
+            (max_width - content_width) / gaps as f64
+        } else {
+            0.0
+        };
+
+        let mut pen = 0.0;
+        for &i in &order {
+            if gap[i] {
+                line[i].advance += extra;
+            }
+            line[i].x = pen;
+            pen += line[i].advance;
+        }
+        trailing_whitespace_width = trailing_whitespace_width.max(pen);
+
+        let (left, right) = line[..content_end]
+            .iter()
+            .map(|c| (c.x, c.x + c.advance))
+            .reduce(|a, b| (a.0.min(b.0), a.1.max(b.1)))
+            .unwrap_or_default();
+        placed.push((first..clusters.len(), para_rtl, left, right - left));
+    }
+
+    let width = if justify {
+        max_width
+    } else {
+        placed.iter().map(|p| p.3).fold(0.0, f64::max)
+    };
+
+    let mut line_starts = Vec::with_capacity(placed.len());
+    for (range, rtl, left, line_width) in placed {
+        let room = width - line_width;
+        let to = match (alignment, rtl) {
+            (TextAlignment::Start | TextAlignment::Justified, false)
+            | (TextAlignment::End, true) => 0.0,
+            (TextAlignment::Start | TextAlignment::Justified, true)
+            | (TextAlignment::End, false) => room,
+            (TextAlignment::Center, _) => room * 0.5,
+        };
+        line_starts.push(if rtl { to + line_width } else { to });
+        for i in range {
+            clusters[i].x += to - left;
+        }
+    }
+
+    Placement {
+        clusters,
+        width,
+        line_starts,
+        trailing_whitespace_width: trailing_whitespace_width.max(width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(
+        text: &str,
+        lines: &[Range<usize>],
+        alignment: TextAlignment,
+        width: f64,
+    ) -> Placement {
+        let advances = vec![10.0; text.len()];
+        place_lines(
+            text,
+            lines,
+            &advances,
+            alignment,
+            width,
+            &TabStops::default(),
+            |_| 0.0,
+        )
+    }
+
+    fn xs(p: &Placement) -> Vec<f64> {
+        p.clusters.iter().map(|c| c.x).collect()
+    }
+
+    // Where glyph_brush_layout puts 10 wide characters on one line,
+    // with a baseline 5 above the bottom.
+    fn origins(text: &str) -> Vec<Option<(f64, f64)>> {
+        let mut origins = vec![None; text.len()];
+        for (n, (i, _)) in text.char_indices().enumerate() {
+            origins[i] = Some((10.0 * n as f64, 5.0));
+        }
+        origins
+    }
+
+    #[test]
+    fn hard_and_soft_breaks() {
+        // Wrapped after "ab ", with an empty line and a trailing one.
+        let text = "ab cd\n\nef\r\n";
+        let wrapped = [(0, 10.0), (1, 10.0), (2, 10.0), (3, 20.0), (4, 20.0)]
+            .into_iter()
+            .chain([(7, 40.0), (8, 40.0)]);
+        assert_eq!(
+            line_ranges(text, wrapped),
+            vec![0..3, 3..6, 6..7, 7..11, 11..11]
+        );
+
+        // Whitespace only lines are still lines.
+        assert_eq!(
+            line_ranges(" \n ", [(0, 10.0), (2, 20.0)]),
+            vec![0..2, 2..3]
+        );
+        assert_eq!(line_ranges("", []), vec![0..0]);
+    }
+
+    #[test]
+    fn tallest_font_sets_metrics() {
+        let text = "ab\n\nc";
+        let lines = line_ranges(text, []);
+        let metrics = line_metrics(
+            text,
+            &lines,
+            |i| match i {
+                1 => (20.0, -5.0, 1.0),
+                _ => (10.0, -2.0, 0.0),
+            },
+            |_| None,
+        );
+        let m: Vec<_> = metrics
+            .iter()
+            .map(|m| {
+                (
+                    m.start_offset,
+                    m.end_offset,
+                    m.baseline,
+                    m.height,
+                    m.y_offset,
+                )
+            })
+            .collect();
+        assert_eq!(
+            m,
+            vec![
+                (0, 3, 20.0, 25.0, 0.0),
+                (3, 4, 10.0, 12.0, 26.0),
+                (4, 5, 10.0, 12.0, 38.0)
+            ]
+        );
+
+        let metrics = line_metrics("a  \nb ", &[0..4, 4..6], |_| (10.0, -2.0, 0.0), |_| None);
+        assert_eq!(metrics[0].trailing_whitespace, 3);
+        assert_eq!(metrics[1].trailing_whitespace, 1);
+    }
+
+    #[test]
+    fn line_height() {
+        // 12 high fonts on 20 high lines, except for "b" on the first.
+        let metrics = line_metrics(
+            "ab\nc",
+            &[0..3, 3..4],
+            |_| (10.0, -2.0, 1.0),
+            |i| Some(if i == 1 { 30.0 } else { 20.0 }),
+        );
+        let m: Vec<_> = metrics
+            .iter()
+            .map(|m| (m.baseline, m.height, m.y_offset))
+            .collect();
+        assert_eq!(m, vec![(19.0, 30.0, 0.0), (14.0, 20.0, 30.0)]);
+
+        // Lines without one keep the font's.
+        let metrics = line_metrics(
+            "a\nb",
+            &[0..2, 2..3],
+            |_| (10.0, -2.0, 1.0),
+            |i| if i == 2 { Some(20.0) } else { None },
+        );
+        assert_eq!(metrics[0].height, 12.0);
+        assert_eq!(metrics[1].y_offset, 13.0);
+    }
+
+    #[test]
+    fn glyphs_on_line_baselines() {
+        // Two lines at twice the 12 high font's size. glyph_brush_layout
+        // placed them 13 apart, with baselines 15 and 2 above the
+        // bottom of its layout.
+        let text = "a\nb";
+        let metrics = line_metrics(
+            text,
+            &[0..2, 2..3],
+            |_| (10.0, -2.0, 1.0),
+            |_| Some(2.0 * 12.0),
+        );
+        let height = metrics[1].y_offset + metrics[1].height;
+        assert_eq!(height, 48.0);
+        let advances = vec![10.0; text.len()];
+        let p = place_lines(
+            text,
+            &[0..2, 2..3],
+            &advances,
+            TextAlignment::Start,
+            f64::MAX,
+            &TabStops::default(),
+            |_| 0.0,
+        );
+        let origins = [Some((0.0, 15.0)), Some((10.0, 15.0)), Some((0.0, 2.0))];
+        let shifts = cluster_shifts(&p.clusters, &metrics, height, &origins);
+        assert_eq!(shifts, vec![(0.0, 17.0), (0.0, 17.0), (0.0, 6.0)]);
+
+        // A glyph centered 3 above its baseline on each line.
+        let ys: Vec<_> = [(0, 18.0), (2, 5.0)]
+            .iter()
+            .map(|&(i, y)| y + shifts[cluster_index(&p.clusters, i).unwrap()].1)
+            .collect();
+        assert_eq!(ys, vec![35.0, 11.0]);
+        // 24 apart, each 3 above its line's baseline from the top.
+        for (y, l) in ys.iter().zip(&metrics) {
+            assert_eq!(height - y, l.y_offset + l.baseline - 3.0);
+        }
+
+        assert_eq!(
+            cluster_shifts(&p.clusters, &metrics, height, &[]),
+            vec![(0.0, 0.0); 3]
+        );
+    }
+
+    #[test]
+    fn letter_spacing() {
+        let text = "ab\tc";
+        let advances = vec![10.0; text.len()];
+        let all = 0..text.len();
+        let p = place_lines(
+            text,
+            std::slice::from_ref(&all),
+            &advances,
+            TextAlignment::Start,
+            f64::MAX,
+            &TabStops::Spaces(4),
+            |i| if i < 2 { 2.0 } else { 0.0 },
+        );
+        // The tab still stops at 40.
+        assert_eq!(xs(&p), vec![0.0, 12.0, 24.0, 40.0]);
+        assert_eq!(p.width, 50.0);
+    }
+
+    #[test]
+    fn ltr() {
+        let all = 0..5;
+        let p = place("ab cd", &[all], TextAlignment::Start, f64::MAX);
+        assert_eq!(xs(&p), vec![0.0, 10.0, 20.0, 30.0, 40.0]);
+        assert_eq!(p.width, 50.0);
+
+        // Trailing whitespace doesn't count for alignment.
+        let p = place("ab \ncd", &[0..4, 4..6], TextAlignment::End, f64::MAX);
+        assert_eq!(xs(&p), vec![0.0, 10.0, 20.0, 30.0, 0.0, 10.0]);
+        assert_eq!(p.width, 20.0);
+        assert_eq!(p.trailing_whitespace_width, 30.0);
+        let p = place("abc\nd", &[0..4, 4..5], TextAlignment::Center, f64::MAX);
+        assert_eq!(p.clusters[4].x, 10.0);
+        assert_eq!(p.line_starts, vec![0.0, 10.0]);
+
+        // An empty last line still has a start.
+        let p = place("abc\n", &[0..4, 4..4], TextAlignment::End, f64::MAX);
+        assert_eq!(p.line_starts, vec![0.0, 30.0]);
+        let p = place("", &[0..0], TextAlignment::Center, f64::MAX);
+        assert_eq!(p.line_starts, vec![0.0]);
+    }
+
+    #[test]
+    fn rtl() {
+        // Hebrew letters are two bytes each.
+        let text = "אב גד";
+        let all = 0..text.len();
+        let p = place(text, &[all], TextAlignment::Start, f64::MAX);
+        assert!(p.clusters.iter().all(|c| c.rtl));
+        // Right to left: א is rightmost.
+        assert_eq!(xs(&p), vec![40.0, 30.0, 20.0, 10.0, 0.0]);
+        assert_eq!(p.clusters[0].leading_edge(), 50.0);
+
+        // An RTL paragraph starts on the right.
+        let text = "אב\nabcd";
+        let p = place(text, &[0..5, 5..9], TextAlignment::Start, f64::MAX);
+        assert_eq!(p.clusters[0].x, 30.0);
+        assert_eq!(p.clusters[1].x, 20.0);
+        assert_eq!(p.line_starts, vec![40.0, 0.0]);
+    }
+
+    #[test]
+    fn mixed() {
+        // "ab אב cd": the Hebrew word is reversed in place.
+        let text = "ab אב cd";
+        let all = 0..text.len();
+        let p = place(text, &[all], TextAlignment::Start, f64::MAX);
+        let x: Vec<_> = p.clusters.iter().map(|c| (c.byte_index, c.x)).collect();
+        assert_eq!(
+            x,
+            vec![
+                (0, 0.0),
+                (1, 10.0),
+                (2, 20.0),
+                (3, 40.0),
+                (5, 30.0),
+                (7, 50.0),
+                (8, 60.0),
+                (9, 70.0)
+            ]
+        );
+        // "b אב" is split in two by the reversed word.
+        assert_eq!(
+            spans(&p.clusters, 1..5),
+            vec![(0, 10.0, 30.0), (0, 40.0, 50.0)]
+        );
+
+        // Glyphs move from where they were laid out left to right.
+        let all = 0..text.len();
+        let metrics = line_metrics(text, &[all], |_| (10.0, -2.0, 0.0), |_| None);
+        let shifts = cluster_shifts(&p.clusters, &metrics, 12.0, &origins(text));
+        assert_eq!(shifts[3].0, 10.0);
+        assert_eq!(shifts[4].0, -10.0);
+    }
+
+    #[test]
+    fn graphemes() {
+        // A combining accent and a skin toned emoji.
+        let text = "e\u{301}\u{1F44D}\u{1F3FD}!";
+        let all = 0..text.len();
+        let p = place(text, &[all], TextAlignment::Start, f64::MAX);
+        let c: Vec<_> = p
+            .clusters
+            .iter()
+            .map(|c| (c.byte_index, c.len, c.x))
+            .collect();
+        assert_eq!(c, vec![(0, 3, 0.0), (3, 8, 20.0), (11, 1, 40.0)]);
+        assert_eq!(cluster_index(&p.clusters, 5), Some(1));
+        assert_eq!(cluster_index(&p.clusters, 12), None);
+    }
+
+    #[test]
+    fn fit() {
+        // The emoji is one 20 wide cluster.
+        let text = "abc\u{1F44D}\u{1F3FD}ef";
+        let all = 0..text.len();
+        let p = place(text, &[all], TextAlignment::Start, f64::MAX);
+        assert_eq!(fit_from_start(&p.clusters, 0..text.len(), 35.0), 3);
+        assert_eq!(fit_from_start(&p.clusters, 0..text.len(), 55.0), 11);
+        assert_eq!(fit_from_start(&p.clusters, 1..text.len(), 100.0), 13);
+        assert_eq!(fit_from_end(&p.clusters, 0..text.len(), 30.0), 11);
+        assert_eq!(fit_from_end(&p.clusters, 0..text.len(), 40.0), 3);
+        assert_eq!(fit_from_end(&p.clusters, 0..3, 100.0), 0);
+    }
+
+    #[test]
+    fn tabs() {
+        let text = "a\tb\tc\td";
+        let advances = vec![10.0; text.len()];
+        let all = 0..text.len();
+        let place = |stops| {
+            place_lines(
+                text,
+                std::slice::from_ref(&all),
+                &advances,
+                TextAlignment::Start,
+                f64::MAX,
+                &stops,
+                |_| 0.0,
+            )
+        };
+
+        let p = place(TabStops::Spaces(4));
+        assert_eq!(xs(&p), vec![0.0, 10.0, 40.0, 50.0, 80.0, 90.0, 120.0]);
+        // "b" was laid out after a space-wide tab.
+        let metrics = line_metrics(
+            text,
+            std::slice::from_ref(&all),
+            |_| (10.0, -2.0, 0.0),
+            |_| None,
+        );
+        let shifts = cluster_shifts(&p.clusters, &metrics, 12.0, &origins(text));
+        assert_eq!(shifts[2].0, 20.0);
+
+        // Past the last stop, a tab is a space.
+        let p = place(TabStops::Positions(vec![25.0, 60.0]));
+        assert_eq!(xs(&p), vec![0.0, 10.0, 25.0, 35.0, 60.0, 70.0, 80.0]);
+    }
+
+    #[test]
+    fn wrap_spaced() {
+        // glyph_brush_layout broke `text` before `soft`.
+        let wrap = |text: &str, soft: &[usize], width, stops, spacing| {
+            let ends: Vec<_> = soft.iter().copied().chain([text.len()]).collect();
+            let lines: Vec<_> = [0]
+                .iter()
+                .chain(&ends)
+                .zip(&ends)
+                .map(|(&a, &b)| a..b)
+                .collect();
+            let advances = vec![10.0; text.len()];
+            let lines = wrap_lines(text, &lines, &advances, width, &stops, |_| spacing);
+            let p = place_lines(
+                text,
+                &lines,
+                &advances,
+                TextAlignment::Start,
+                width,
+                &stops,
+                |_| spacing,
+            );
+            assert!(p.width <= width);
+            lines
+        };
+
+        // Each word is 24 wide with its spacing, so only one fits.
+        assert_eq!(
+            wrap("ab cd ef", &[6], 55.0, TabStops::default(), 2.0),
+            vec![0..3, 3..6, 6..8]
+        );
+        // Lines that fit are left alone.
+        assert_eq!(
+            wrap("ab cd ef", &[6], 60.0, TabStops::default(), 2.0),
+            vec![0..6, 6..8]
+        );
+        // A word wider than the line breaks between characters.
+        assert_eq!(
+            wrap("abcdef", &[], 40.0, TabStops::default(), 2.0),
+            vec![0..3, 3..6]
+        );
+        // The tab takes "b" past 35.
+        assert_eq!(
+            wrap("a\tb", &[], 35.0, TabStops::Spaces(4), 0.0),
+            vec![0..2, 2..3]
+        );
+
+        // A line that can't break stays whole.
+        let all = 0..1;
+        let lines = std::slice::from_ref(&all);
+        let stops = TabStops::default();
+        assert_eq!(wrap_lines("a", lines, &[10.0], 5.0, &stops, |_| 0.0), lines);
+    }
+
+    #[test]
+    fn justified() {
+        let p = place("ab cd ef", &[0..6, 6..8], TextAlignment::Justified, 100.0);
+        // 50 of content and one gap on the first line.
+        assert_eq!(xs(&p), vec![0.0, 10.0, 20.0, 80.0, 90.0, 100.0, 0.0, 10.0]);
+        assert_eq!(p.width, 100.0);
+
+        // The last line of a paragraph is not justified.
+        let p = place("a b\nc", &[0..4, 4..5], TextAlignment::Justified, 100.0);
+        assert_eq!(p.clusters[2].x, 20.0);
+    }
+}
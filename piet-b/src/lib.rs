@@ -1,10 +1,11 @@
 use bevy::{
     ecs::system::{EntityCommands, SystemParam},
+    log::warn,
     math::{Affine2, Affine3A, Mat3A, Vec2},
     prelude::{
-        App, AssetServer, Assets, Bundle, Commands, Component, ComputedVisibility, Entity,
-        GlobalTransform, Handle, Image as BevyImage, Plugin, Query, Res, ResMut, TextureAtlas,
-        Transform, UiCameraConfig, Visibility,
+        App, AssetServer, Assets, Bundle, Commands, Component, ComputedVisibility, CoreStage,
+        Entity, GlobalTransform, Handle, Image as BevyImage, Plugin, Query, Res, ResMut,
+        TextureAtlas, Transform, UiCameraConfig, Visibility,
     },
     render::{
         extract_component::ExtractComponentPlugin,
@@ -12,58 +13,100 @@ use bevy::{
     },
     text::{
         Font, FontAtlasSet, HorizontalAlign, PositionedGlyph, TextError, TextLayoutInfo,
-        TextPipeline, TextSection, TextStyle, VerticalAlign,
+        TextSection, TextStyle, VerticalAlign,
     },
     ui::{CalculatedClip, Node, UiColor, UiImage},
+    utils::HashMap,
     window::{WindowId, Windows},
 };
 use glyph_brush_layout::ab_glyph::{self, ScaleFont};
 use std::{cell::RefCell, sync::Arc};
 
+pub mod cache;
+use cache::LayoutKey;
+pub use cache::{age_text_layouts, TextLayoutCache};
+pub mod display_list;
+pub use display_list::{DisplayList, Recorder};
+pub mod font;
+pub use font::{
+    watch_fonts, Decorations, FaceInfo, FaceSource, FontEvent, FontFace, FontRegistry, DEFAULT_FONT,
+};
+mod layout;
+pub use layout::{Affinity, Cluster, TabStops};
+pub mod pipeline;
+pub use pipeline::PietTextPipeline;
+pub mod raster;
+pub use raster::{rasterize, Raster, RasterParams};
+
 // Piet is reexported; all collisions are prefixed/aliased.
 pub use piet::kurbo;
 pub use piet::*;
 
-pub type NodesQuery<'w, 's> = Query<
-    'w,
-    's,
-    (
-        Entity,
-        &'static Node,
-        &'static UiColor,
-        &'static UiImage,
-        &'static Transform,
-    ),
->;
-
-pub type TextNodesQuery<'w, 's> = Query<
-    'w,
-    's,
-    (
-        Entity,
-        &'static Node,
-        &'static bevy::text::Text,
-        &'static Transform,
-    ),
->;
+/// Identifies the canvas a [`Piet`] draws into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CanvasId(pub u32);
+
+/// Tags every entity spawned by [`Piet`] with the canvas it belongs
+/// to. Clearing only touches entities with this component, so UI
+/// nodes spawned elsewhere are left alone.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct PietNode {
+    pub canvas: CanvasId,
+}
+
+/// An independent drawing surface. Spawn one per layer (HUD, minimap,
+/// debug overlay, etc.) and create a [`Piet`] for it with
+/// [`Piet::with_canvas`]. A canvas id without a component draws over
+/// the whole window at `z` zero.
+#[derive(Component, Clone, Debug, Default)]
+pub struct PietCanvas {
+    pub id: CanvasId,
+    /// The region cleared by `clear(None, ..)`, in dp. `None` is the
+    /// primary window.
+    pub rect: Option<kurbo::Rect>,
+    /// Base depth of this canvas' nodes. Canvases with a higher `z`
+    /// are drawn on top.
+    pub z: f32,
+}
+
+/// How many nodes each canvas has spawned since it was last cleared
+/// in full. This is kept across frames, so nodes drawn after a partial
+/// clear, or without clearing, go on top of the ones still there.
+#[derive(Clone, Debug, Default)]
+pub struct CanvasDepths(HashMap<CanvasId, u32>);
+
+impl CanvasDepths {
+    /// Start `canvas` over at its base depth, as a full clear does.
+    pub fn reset(&mut self, canvas: CanvasId) {
+        self.0.remove(&canvas);
+    }
+}
+
+pub type NodesQuery<'w, 's> =
+    Query<'w, 's, (Entity, &'static PietNode, &'static Node, &'static Transform)>;
+
+pub type CanvasQuery<'w, 's> = Query<'w, 's, &'static PietCanvas>;
 
 #[derive(SystemParam)]
 pub struct PietParams<'w, 's> {
     pub commands: Commands<'w, 's>,
     pub asset_server: Res<'w, AssetServer>,
     pub nodes: NodesQuery<'w, 's>,
-    pub text_nodes: TextNodesQuery<'w, 's>,
+    pub canvases: CanvasQuery<'w, 's>,
+    pub depths: ResMut<'w, CanvasDepths>,
     pub text_params: PietTextParams<'w, 's>,
 }
 
 #[derive(SystemParam)]
 pub struct PietTextParams<'w, 's> {
     pub textures: ResMut<'w, Assets<BevyImage>>,
-    pub fonts: Res<'w, Assets<Font>>,
+    pub fonts: ResMut<'w, Assets<Font>>,
     pub windows: Res<'w, Windows>,
     pub texture_atlases: ResMut<'w, Assets<TextureAtlas>>,
     pub font_atlas_set_storage: ResMut<'w, Assets<FontAtlasSet>>,
-    pub text_pipeline: ResMut<'w, TextPipeline>,
+    pub text_pipeline: ResMut<'w, PietTextPipeline>,
+    pub font_registry: ResMut<'w, FontRegistry>,
+    pub layout_cache: ResMut<'w, TextLayoutCache>,
     #[system_param(ignore)]
     marker: std::marker::PhantomData<&'s usize>,
 }
@@ -73,6 +116,10 @@ impl<'w, 's> PietParams<'w, 's> {
         Piet::new(self, height)
     }
 
+    pub fn piet_canvas(self, canvas: CanvasId, height: f32) -> Piet<'w, 's> {
+        Piet::with_canvas(self, canvas, height)
+    }
+
     pub fn text(self) -> PietText<'w, 's> {
         let PietParams {
             commands,
@@ -97,23 +144,58 @@ pub struct State {
 pub struct Piet<'w, 's> {
     commands: Arc<RefCell<Commands<'w, 's>>>,
     nodes: NodesQuery<'w, 's>,
-    text_nodes: TextNodesQuery<'w, 's>,
     text: PietText<'w, 's>,
+    canvas: CanvasId,
+    canvas_rect: Option<kurbo::Rect>,
+    z: f32,
+    // Each node is placed slightly above the previous one so draw
+    // order is kept, up to max_depth.
+    depths: ResMut<'w, CanvasDepths>,
+    max_depth: u32,
     state: State,
     state_stack: Vec<State>,
     flip_y: Affine2,
 }
 
+// Depth between consecutive nodes in a canvas.
+const Z_STEP: f32 = 1.0e-4;
+
+// The deepest node in a canvas at `z` that is still below the next
+// canvas up.
+fn max_depth(z: f32, canvases: impl Iterator<Item = f32>) -> u32 {
+    canvases
+        .filter(|&c| c > z)
+        .reduce(f32::min)
+        .map_or(u32::MAX, |next| {
+            (((next - z) / Z_STEP) as u32).saturating_sub(1)
+        })
+}
+
 impl<'w, 's> Piet<'w, 's> {
     /// `height` is the height of the drawable area in dp.
     pub fn new(params: PietParams<'w, 's>, height: f32) -> Self {
+        Self::with_canvas(params, CanvasId::default(), height)
+    }
+
+    /// Draw into `canvas`. Nodes belonging to other canvases are not
+    /// touched.
+    pub fn with_canvas(params: PietParams<'w, 's>, canvas: CanvasId, height: f32) -> Self {
         let PietParams {
             commands,
             asset_server,
             nodes,
-            text_nodes,
+            canvases,
+            depths,
             text_params,
         } = params;
+
+        let (canvas_rect, z) = canvases
+            .iter()
+            .find(|c| c.id == canvas)
+            .map(|c| (c.rect, c.z))
+            .unwrap_or_default();
+        let max_depth = max_depth(z, canvases.iter().map(|c| c.z));
+
         let commands = Arc::new(RefCell::new(commands));
         let asset_server = Arc::new(asset_server);
         let text = PietText::new(commands.clone(), asset_server, text_params);
@@ -123,14 +205,33 @@ impl<'w, 's> Piet<'w, 's> {
         Self {
             commands,
             nodes,
-            text_nodes,
             text,
+            canvas,
+            canvas_rect,
+            z,
+            depths,
+            max_depth,
             state: State::default(),
             state_stack: Vec::new(),
             flip_y,
         }
     }
 
+    pub fn canvas(&self) -> CanvasId {
+        self.canvas
+    }
+
+    fn node(&self) -> PietNode {
+        PietNode {
+            canvas: self.canvas,
+        }
+    }
+
+    /// The region this canvas clears, in dp.
+    pub fn canvas_rect(&self) -> kurbo::Rect {
+        self.canvas_rect.unwrap_or_else(|| self.window_rect())
+    }
+
     // Just save on the height on create.
     pub fn window_rect(&self) -> kurbo::Rect {
         let window = self.text.windows.primary();
@@ -139,12 +240,31 @@ impl<'w, 's> Piet<'w, 's> {
         kurbo::Rect::default().with_size((width, height))
     }
 
-    pub fn make_transform(&self, pt: kurbo::Point) -> Transform {
+    /// Draw `layout` in `rect`, aligned vertically.
+    pub fn draw_text_in(
+        &mut self,
+        layout: &PietTextLayout,
+        rect: kurbo::Rect,
+        alignment: VerticalAlignment,
+    ) {
+        self.draw_text(layout, layout.origin_in(rect, alignment));
+    }
+
+    /// Each call places the next node above the previous one, until
+    /// the canvas runs into the next canvas up.
+    pub fn make_transform(&mut self, pt: kurbo::Point) -> Transform {
         let affine =
             Affine2::from_translation(Vec2::new(pt.x as f32, pt.y as f32)) * self.state.transform;
 
-        // TODO:
-        let z = 0.0;
+        let depth = self.depths.0.entry(self.canvas).or_default();
+        if *depth == self.max_depth {
+            warn!(
+                "canvas {:?} reached the next canvas' depth; clear it to keep draw order",
+                self.canvas
+            );
+        }
+        let z = self.z + (*depth).min(self.max_depth) as f32 * Z_STEP;
+        *depth = depth.saturating_add(1);
 
         let aff3 = Affine3A {
             matrix3: Mat3A::from_mat2(affine.matrix2),
@@ -164,22 +284,13 @@ fn convert_color(color: piet::Color) -> bevy::prelude::Color {
     bevy::prelude::Color::rgba_u8(r, g, b, a)
 }
 
-fn convert_alignment(alignment: piet::TextAlignment) -> bevy::text::TextAlignment {
-    match alignment {
-        // ignoring right to left for now
-        piet::TextAlignment::Start => bevy::text::TextAlignment {
-            vertical: VerticalAlign::Center,
-            horizontal: HorizontalAlign::Left,
-        },
-        piet::TextAlignment::End => bevy::text::TextAlignment {
-            vertical: VerticalAlign::Center,
-            horizontal: HorizontalAlign::Right,
-        },
-        piet::TextAlignment::Center => bevy::text::TextAlignment {
-            vertical: VerticalAlign::Center,
-            horizontal: HorizontalAlign::Center,
-        },
-        piet::TextAlignment::Justified => unimplemented!(),
+// Lines are laid out left aligned and placed afterwards. See
+// layout::place_lines. The pipeline lays them out down from the top,
+// and the glyphs are moved to their lines' baselines after.
+fn convert_alignment() -> bevy::text::TextAlignment {
+    bevy::text::TextAlignment {
+        vertical: VerticalAlign::Top,
+        horizontal: HorizontalAlign::Left,
     }
 }
 
@@ -237,25 +348,39 @@ impl<'w, 's> piet::RenderContext for Piet<'w, 's> {
         Ok(Brush::Solid(piet::Color::GRAY))
     }
 
-    // TODO: Partial clearing of entities.
+    // Partial clears remove nodes that are entirely inside the
+    // region. Nodes that overlap the edge are kept.
     fn clear(&mut self, region: impl Into<Option<kurbo::Rect>>, color: piet::Color) {
-        match region.into() {
-            //Some(_) => unimplemented!(),
-            _ => {
-                {
-                    let mut commands = self.commands.borrow_mut();
-                    for (entity, ..) in self.nodes.iter() {
-                        commands.entity(entity).despawn();
-                    }
-                    for (entity, ..) in self.text_nodes.iter() {
-                        commands.entity(entity).despawn();
-                    }
+        let region = region.into();
+        {
+            let mut commands = self.commands.borrow_mut();
+            let unflip_y = self.flip_y.inverse();
+            for (entity, node, ui_node, transform) in self.nodes.iter() {
+                if node.canvas != self.canvas {
+                    continue;
                 }
-                if color != piet::Color::TRANSPARENT {
-                    self.fill(self.window_rect(), &color);
+                let inside = region.map_or(true, |region| {
+                    let center = unflip_y.transform_point2(transform.translation.truncate());
+                    let rect = kurbo::Rect::from_center_size(
+                        (center.x as f64, center.y as f64),
+                        (ui_node.size.x as f64, ui_node.size.y as f64),
+                    );
+                    region.union(rect) == region
+                });
+                if inside {
+                    commands.entity(entity).despawn();
                 }
             }
         }
+
+        if region.is_none() {
+            self.depths.reset(self.canvas);
+        }
+
+        if color != piet::Color::TRANSPARENT {
+            let rect = region.unwrap_or_else(|| self.canvas_rect());
+            self.fill(rect, &color);
+        }
     }
 
     fn stroke(&mut self, shape: impl kurbo::Shape, brush: &impl piet::IntoBrush<Self>, width: f64) {
@@ -293,6 +418,7 @@ impl<'w, 's> piet::RenderContext for Piet<'w, 's> {
                     },
                     color: UiColor(color),
                     transform,
+                    piet_node: self.node(),
                     ..Default::default()
                 })
                 .maybe_insert(self.state.clip);
@@ -338,6 +464,7 @@ impl<'w, 's> piet::RenderContext for Piet<'w, 's> {
                 // Clone out of the Arc.
                 text: (*layout.render_text).clone(),
                 transform,
+                piet_node: self.node(),
                 ..Default::default()
             })
             // Manual insert of glyphs. Clone out of the Arc. This
@@ -346,6 +473,12 @@ impl<'w, 's> piet::RenderContext for Piet<'w, 's> {
             // dropped. Unless we store a reference w/ the entity?
             .insert((*layout.text_layout_info).clone())
             .maybe_insert(self.state.clip);
+
+        let origin = rect.origin().to_vec2();
+        for &(line, color) in layout.decorations.iter() {
+            let brush = self.solid_brush(color);
+            self.fill(line + origin, &brush);
+        }
     }
 
     fn save(&mut self) -> Result<(), piet::Error> {
@@ -417,6 +550,7 @@ impl<'w, 's> piet::RenderContext for Piet<'w, 's> {
                 },
                 image: UiImage(image.0.clone()),
                 transform,
+                piet_node: self.node(),
                 ..Default::default()
             })
             .maybe_insert(self.state.clip);
@@ -476,11 +610,13 @@ pub struct PietText<'w, 's> {
     pub commands: Arc<RefCell<Commands<'w, 's>>>,
     pub asset_server: Arc<Res<'w, AssetServer>>,
     pub textures: Arc<RefCell<ResMut<'w, Assets<BevyImage>>>>,
-    pub fonts: Arc<Res<'w, Assets<Font>>>,
+    pub fonts: Arc<RefCell<ResMut<'w, Assets<Font>>>>,
     pub windows: Arc<Res<'w, Windows>>,
     pub texture_atlases: Arc<RefCell<ResMut<'w, Assets<TextureAtlas>>>>,
     pub font_atlas_set_storage: Arc<RefCell<ResMut<'w, Assets<FontAtlasSet>>>>,
-    pub text_pipeline: Arc<RefCell<ResMut<'w, TextPipeline>>>,
+    pub text_pipeline: Arc<RefCell<ResMut<'w, PietTextPipeline>>>,
+    pub font_registry: Arc<RefCell<ResMut<'w, FontRegistry>>>,
+    pub layout_cache: Arc<RefCell<ResMut<'w, TextLayoutCache>>>,
 }
 
 impl<'w, 's> PietText<'w, 's> {
@@ -496,6 +632,8 @@ impl<'w, 's> PietText<'w, 's> {
             texture_atlases,
             font_atlas_set_storage,
             text_pipeline,
+            font_registry,
+            layout_cache,
             ..
         } = params;
 
@@ -503,11 +641,13 @@ impl<'w, 's> PietText<'w, 's> {
             commands,
             asset_server,
             textures: Arc::new(textures.into()),
-            fonts: fonts.into(),
+            fonts: Arc::new(fonts.into()),
             windows: windows.into(),
             texture_atlases: Arc::new(texture_atlases.into()),
             font_atlas_set_storage: Arc::new(font_atlas_set_storage.into()),
             text_pipeline: Arc::new(text_pipeline.into()),
+            font_registry: Arc::new(font_registry.into()),
+            layout_cache: Arc::new(layout_cache.into()),
         }
     }
 }
@@ -524,11 +664,18 @@ impl<'w, 's> piet::Text for PietText<'w, 's> {
     type TextLayout = PietTextLayout;
 
     fn font_family(&mut self, family_name: &str) -> Option<piet::FontFamily> {
-        Some(piet::FontFamily::new_unchecked(family_name))
+        self.font_registry
+            .borrow()
+            .family_name(family_name)
+            .map(piet::FontFamily::new_unchecked)
     }
 
-    fn load_font(&mut self, _data: &[u8]) -> Result<piet::FontFamily, piet::Error> {
-        unimplemented!()
+    fn load_font(&mut self, data: &[u8]) -> Result<piet::FontFamily, piet::Error> {
+        let mut fonts = self.fonts.borrow_mut();
+        self.font_registry
+            .borrow_mut()
+            .add_font(&mut fonts, data.to_vec())
+            .map(piet::FontFamily::new_unchecked)
     }
 
     fn new_text_layout(&mut self, text: impl piet::TextStorage) -> Self::TextLayoutBuilder {
@@ -538,13 +685,32 @@ impl<'w, 's> piet::Text for PietText<'w, 's> {
             params: self.clone(),
             max_width: f64::MAX,
             alignment: piet::TextAlignment::Start,
-            color: piet::util::DEFAULT_TEXT_COLOR,
-            font: None,
-            size: piet::util::DEFAULT_FONT_SIZE,
+            overflow: Overflow::default(),
+            tab_stops: TabStops::default(),
+            defaults: Attributes::default(),
+            ranges: Vec::new(),
         }
     }
 }
 
+/// How a layout sits in a rect of a different height.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VerticalAlignment {
+    /// The top of the layout on the top of the rect.
+    Top,
+    /// In the middle of the rect.
+    Center,
+    /// The first line's baseline on the bottom of the rect, with its
+    /// descenders below.
+    Baseline,
+}
+
+impl Default for VerticalAlignment {
+    fn default() -> Self {
+        VerticalAlignment::Top
+    }
+}
+
 // This struct persists inside widgets and needs to hold onto
 // everything it needs to fulfill the impl.
 #[derive(Clone)]
@@ -556,9 +722,130 @@ pub struct PietTextLayout {
     pub size: kurbo::Size,
     pub line_metrics: Arc<[piet::LineMetric]>,
     pub image_bounds: kurbo::Rect,
+    /// Where each character ended up, in logical order.
+    pub clusters: Arc<[Cluster]>,
+    /// Where the caret goes at the start of each line.
+    pub line_starts: Arc<[f64]>,
+    /// Underlines and strikethroughs.
+    pub decorations: Arc<[(kurbo::Rect, piet::Color)]>,
+    pub trailing_whitespace_width: f64,
+    /// Laid out with a stand-in for a font that was still loading.
+    /// Build it again on [`FontEvent::Loaded`].
+    pub provisional: bool,
 }
 
 impl PietTextLayout {
+    /// Like [`hit_test_point`](piet::TextLayout::hit_test_point), and
+    /// also which side of the returned offset was hit.
+    pub fn hit_test_point_affinity(&self, point: kurbo::Point) -> (piet::HitTestPoint, Affinity) {
+        let n = self
+            .line_metrics
+            .iter()
+            .position(|l| point.y <= l.y_offset + l.height)
+            .unwrap_or_else(|| self.line_metrics.len().saturating_sub(1));
+        let l = match self.line_metrics.get(n) {
+            Some(l) => l,
+            None => return Default::default(),
+        };
+
+        // The line's clusters from left to right.
+        let mut line: Vec<_> = self
+            .clusters
+            .iter()
+            .filter(|c| c.line == n && !self.is_newline(c))
+            .collect();
+        line.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap_or(std::cmp::Ordering::Equal));
+        let (first, last) = match (line.first(), line.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                return (
+                    piet::HitTestPoint::new(l.start_offset, false),
+                    Affinity::Leading,
+                )
+            }
+        };
+
+        let c = line
+            .iter()
+            .find(|c| point.x < c.x + c.advance)
+            .unwrap_or(last);
+        // The caret goes on the side of the cluster that was hit,
+        // which is its end for right to left text.
+        let (idx, affinity) = if (point.x > c.x + c.advance * 0.5) != c.rtl {
+            (c.byte_index + c.len, Affinity::Trailing)
+        } else {
+            (c.byte_index, Affinity::Leading)
+        };
+        let is_inside = point.y >= l.y_offset
+            && point.y <= l.y_offset + l.height
+            && point.x >= first.x
+            && point.x <= last.x + last.advance;
+        (piet::HitTestPoint::new(idx, is_inside), affinity)
+    }
+
+    /// Like [`hit_test_text_position`](piet::TextLayout::hit_test_text_position),
+    /// with the caret against the cluster on the `affinity` side of
+    /// `idx`. They differ at soft line breaks, where the trailing
+    /// side is the end of the line above, and between runs of
+    /// different direction.
+    pub fn hit_test_text_position_affinity(
+        &self,
+        idx: usize,
+        affinity: Affinity,
+    ) -> piet::HitTestPosition {
+        // Inside a cluster, the caret goes before it.
+        let idx = idx.min(self.text.len());
+        let idx =
+            layout::cluster_index(&self.clusters, idx).map_or(idx, |i| self.clusters[i].byte_index);
+
+        let next = self
+            .clusters
+            .binary_search_by_key(&idx, |c| c.byte_index)
+            .ok()
+            .map(|i| &self.clusters[i]);
+        // Not across a line break.
+        let prev = idx
+            .checked_sub(1)
+            .and_then(|i| layout::cluster_index(&self.clusters, i))
+            .map(|i| &self.clusters[i])
+            .filter(|c| !self.is_newline(c));
+        let (x, n) = match (affinity, prev, next) {
+            (Affinity::Trailing, Some(c), _) | (_, Some(c), None) => (c.trailing_edge(), c.line),
+            (_, _, Some(c)) => (c.leading_edge(), c.line),
+            // An empty line.
+            (_, None, None) => {
+                let n = piet::util::line_number_for_position(&self.line_metrics, idx);
+                (self.line_starts.get(n).copied().unwrap_or_default(), n)
+            }
+        };
+        let baseline = self
+            .line_metrics
+            .get(n)
+            .map_or(0.0, |l| l.y_offset + l.baseline);
+        piet::HitTestPosition::new(kurbo::Point::new(x, baseline), n)
+    }
+
+    fn is_newline(&self, cluster: &Cluster) -> bool {
+        self.text[cluster.byte_index..].starts_with(layout::is_newline)
+    }
+
+    /// Where to draw this layout to align it vertically in `rect`. It
+    /// starts at the rect's left edge.
+    pub fn origin_in(&self, rect: kurbo::Rect, alignment: VerticalAlignment) -> kurbo::Point {
+        let y = match alignment {
+            VerticalAlignment::Top => rect.y0,
+            VerticalAlignment::Center => rect.center().y - self.size.height * 0.5,
+            VerticalAlignment::Baseline => {
+                rect.y1
+                    - self
+                        .line_metrics
+                        .first()
+                        .map_or(self.size.height, |l| l.y_offset + l.baseline)
+            }
+        };
+        kurbo::Point::new(rect.x0, y)
+    }
+
     pub fn glyph_rect(&self, glyph: &PositionedGlyph) -> kurbo::Rect {
         // the glyph position is the center
         kurbo::Rect::from_center_size(
@@ -570,32 +857,38 @@ impl PietTextLayout {
             (glyph.size.x as f64, glyph.size.y as f64),
         )
     }
+}
 
-    // Returns a slice of glyphs in the specified byte range. This
-    // assumes glyphs are sorted by byte_index.
-    fn glyph_range(&self, range: std::ops::Range<usize>) -> Option<&[PositionedGlyph]> {
-        self.glyphs
-            .iter()
-            .position(|g| g.byte_index >= range.start)
-            .and_then(|start| {
-                self.glyphs[start..]
-                    .iter()
-                    .rposition(|g| g.byte_index < range.end)
-                    .map(|end| {
-                        let end = start + end + 1; // exclusive
-                        &self.glyphs[start..end]
-                    })
-            })
+#[cfg(test)]
+impl PietTextLayout {
+    /// `text` with no lines or glyphs, for tests to fill in.
+    pub(crate) fn empty(text: &str) -> Self {
+        Self {
+            text: text.into(),
+            glyphs: Default::default(),
+            text_layout_info: Arc::new(TextLayoutInfo {
+                glyphs: Vec::new(),
+                size: Vec2::ZERO,
+            }),
+            render_text: Default::default(),
+            size: kurbo::Size::ZERO,
+            line_metrics: Arc::new([]),
+            image_bounds: kurbo::Rect::ZERO,
+            clusters: Arc::new([]),
+            line_starts: Arc::new([]),
+            decorations: Arc::new([]),
+            trailing_whitespace_width: 0.0,
+            provisional: false,
+        }
     }
 }
 
 impl std::fmt::Display for PietTextLayout {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let chars: Vec<_> = self.text.chars().collect();
         let glyphs: Vec<_> = self
             .glyphs
             .iter()
-            .map(|g| (g.byte_index, chars[g.byte_index]))
+            .filter_map(|g| Some((g.byte_index, self.text[g.byte_index..].chars().next()?)))
             .collect();
         write!(f, "{:?}", glyphs)
     }
@@ -603,14 +896,12 @@ impl std::fmt::Display for PietTextLayout {
 
 impl piet::TextLayout for PietTextLayout {
     fn size(&self) -> kurbo::Size {
-        // this is CalculatedSize? it needs to include cursor height
-        // for empty text
+        // this is CalculatedSize? Empty text is one line high.
         self.size
     }
 
-    // TODO:
     fn trailing_whitespace_width(&self) -> f64 {
-        0.
+        self.trailing_whitespace_width
     }
 
     fn image_bounds(&self) -> kurbo::Rect {
@@ -628,7 +919,6 @@ impl piet::TextLayout for PietTextLayout {
     }
 
     fn line_metric(&self, line_number: usize) -> Option<piet::LineMetric> {
-        // TODO: if line_number == 0 && self.text.is_empty() {
         self.line_metrics.get(line_number).cloned()
     }
 
@@ -637,57 +927,258 @@ impl piet::TextLayout for PietTextLayout {
     }
 
     fn hit_test_point(&self, point: kurbo::Point) -> piet::HitTestPoint {
-        self.line_metrics
+        self.hit_test_point_affinity(point).0
+    }
+
+    fn hit_test_text_position(&self, idx: usize) -> piet::HitTestPosition {
+        self.hit_test_text_position_affinity(idx, Affinity::Leading)
+    }
+
+    /// One rect per visual run of selected clusters, so usually one
+    /// per line. Selected trailing whitespace is included, clipped to
+    /// the layout's width, and rects extend down to the next line so
+    /// multi-line selections are contiguous. Line breaks get none.
+    fn rects_for_range(&self, range: impl std::ops::RangeBounds<usize>) -> Vec<kurbo::Rect> {
+        let range = piet::util::resolve_range(range, self.text.len());
+        let clusters: Vec<_> = self
+            .clusters
             .iter()
-            .find(|l| point.y <= (l.y_offset + l.height))
-            .or_else(|| self.line_metrics.last())
-            // Save glyph ranges per line on build?
-            .and_then(|l| self.glyph_range(l.range()))
-            .and_then(|gs| {
-                if let Some(g) = gs.iter().find(|g| self.glyph_rect(g).contains(point)) {
-                    Some(piet::HitTestPoint::new(g.byte_index, true))
-                } else {
-                    // Find closest glyph to point via center. This
-                    // should use the left edge.
-                    let point = Vec2::new(point.x as f32, (self.size.height - point.y) as f32);
-                    // min_by_key requires Ord
-                    gs.iter()
-                        .map(|g| (g.position.distance_squared(point), g.byte_index))
-                        .reduce(|a, b| if b.0 < a.0 { b } else { a })
-                        .map(|(_, byte_index)| piet::HitTestPoint::new(byte_index, false))
-                }
+            .filter(|c| !self.is_newline(c))
+            .copied()
+            .collect();
+        layout::spans(&clusters, range)
+            .into_iter()
+            .filter_map(|(line, x0, x1)| {
+                let l = self.line_metrics.get(line)?;
+                let y1 = self
+                    .line_metrics
+                    .get(line + 1)
+                    .map_or(l.y_offset + l.height, |next| next.y_offset);
+                // Trailing whitespace may be clipped away entirely.
+                let x1 = x1.min(self.size.width);
+                (x1 > x0).then_some(kurbo::Rect::new(x0, l.y_offset, x1, y1))
             })
-            .unwrap_or_default()
+            .collect()
     }
+}
 
-    // If the offset is whitespace we will get the previous glyph?
-    fn hit_test_text_position(&self, idx: usize) -> piet::HitTestPosition {
-        let idx = idx.min(self.text.len());
-        assert!(self.text.is_char_boundary(idx));
-        let n = piet::util::line_number_for_position(&self.line_metrics, idx);
-        let l = &self.line_metrics[n];
-        let gs = self.glyph_range(l.range()).unwrap();
-        let g = &gs[gs
-            .binary_search_by_key(&idx, |g| g.byte_index)
-            .unwrap_or_else(|n| n.saturating_sub(1))];
-        let point = kurbo::Point::new(
-            (g.position.x - g.size.x * 0.5) as f64,
-            (l.y_offset + l.baseline) as f64,
-        );
+/// What to do with text that doesn't fit a layout's max width.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Overflow {
+    /// Wrap onto as many lines as it takes.
+    Visible,
+    /// Keep the first line, without wrapping, and cut it off at the
+    /// last character that fits.
+    Clip,
+    /// Like `Clip`, but end with an ellipsis.
+    Ellipsis,
+    /// Keep the first line, without wrapping, replacing its middle
+    /// with an ellipsis.
+    EllipsisMiddle,
+    /// Wrap onto at most this many lines, ending with an ellipsis.
+    MaxLines(usize),
+}
 
-        piet::HitTestPosition::new(point, n)
+impl Default for Overflow {
+    fn default() -> Self {
+        Self::Visible
     }
 }
 
-// TODO: split attributes into sections?
 pub struct PietTextLayoutBuilder<'w, 's> {
     text: Arc<str>,
     params: PietText<'w, 's>,
     max_width: f64,
     alignment: piet::TextAlignment,
-    color: piet::Color,
+    overflow: Overflow,
+    tab_stops: TabStops,
+    defaults: Attributes,
+    // In the order they were added; later ranges win.
+    ranges: Vec<(std::ops::Range<usize>, Attribute)>,
+}
+
+/// The distance from one line to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineHeight {
+    /// The font's ascent, descent and line gap.
+    Normal,
+    /// This many times the font size.
+    Multiple(f64),
+    /// In dp.
+    Fixed(f64),
+}
+
+impl Default for LineHeight {
+    fn default() -> Self {
+        LineHeight::Normal
+    }
+}
+
+// A piet attribute, or one of ours.
+enum Attribute {
+    Piet(piet::TextAttribute),
+    LetterSpacing(f64),
+    LineHeight(LineHeight),
+}
+
+// The attributes for a run of text. Each run becomes a TextSection.
+#[derive(Clone, Debug, PartialEq)]
+struct Attributes {
     font: Option<piet::FontFamily>,
     size: f64,
+    weight: piet::FontWeight,
+    style: piet::FontStyle,
+    color: piet::Color,
+    underline: bool,
+    strikethrough: bool,
+    letter_spacing: f64,
+    line_height: LineHeight,
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self {
+            font: None,
+            size: piet::util::DEFAULT_FONT_SIZE,
+            weight: piet::FontWeight::REGULAR,
+            style: piet::FontStyle::Regular,
+            color: piet::util::DEFAULT_TEXT_COLOR,
+            underline: false,
+            strikethrough: false,
+            letter_spacing: 0.0,
+            line_height: LineHeight::Normal,
+        }
+    }
+}
+
+impl Attributes {
+    fn set(&mut self, attr: &piet::TextAttribute) {
+        match *attr {
+            piet::TextAttribute::FontFamily(ref font) => self.font = Some(font.clone()),
+            piet::TextAttribute::FontSize(size) => self.size = size,
+            piet::TextAttribute::Weight(weight) => self.weight = weight,
+            piet::TextAttribute::Style(style) => self.style = style,
+            piet::TextAttribute::TextColor(ref color) => self.color = color.clone(),
+            piet::TextAttribute::Underline(underline) => self.underline = underline,
+            piet::TextAttribute::Strikethrough(strikethrough) => self.strikethrough = strikethrough,
+        }
+    }
+
+    fn apply(&mut self, attr: &Attribute) {
+        match *attr {
+            Attribute::Piet(ref attr) => self.set(attr),
+            Attribute::LetterSpacing(spacing) => self.letter_spacing = spacing,
+            Attribute::LineHeight(height) => self.line_height = height,
+        }
+    }
+
+    // The explicit line height, in dp.
+    fn line_height(&self) -> Option<f64> {
+        match self.line_height {
+            LineHeight::Normal => None,
+            LineHeight::Multiple(m) => Some(m * self.size),
+            LineHeight::Fixed(h) => Some(h),
+        }
+    }
+}
+
+const ELLIPSIS: &str = "\u{2026}";
+
+// The text made of `pieces` of `text`, with an ellipsis for each
+// `None`, and its runs of attributes. An ellipsis takes the attributes
+// of the text before it.
+fn splice(
+    text: &str,
+    runs: &[(std::ops::Range<usize>, Attributes)],
+    pieces: &[Option<std::ops::Range<usize>>],
+) -> (String, Vec<(std::ops::Range<usize>, Attributes)>) {
+    let first = runs
+        .first()
+        .map(|(_, attrs)| attrs.clone())
+        .unwrap_or_default();
+    let mut out = String::new();
+    let mut out_runs: Vec<(std::ops::Range<usize>, Attributes)> = Vec::new();
+    for piece in pieces {
+        let spans: Vec<(&str, Attributes)> = match piece {
+            Some(piece) => runs
+                .iter()
+                .filter_map(|(r, attrs)| {
+                    let r = r.start.max(piece.start)..r.end.min(piece.end);
+                    (!r.is_empty()).then(|| (&text[r], attrs.clone()))
+                })
+                .collect(),
+            None => {
+                let attrs = out_runs
+                    .last()
+                    .map_or_else(|| first.clone(), |(_, a)| a.clone());
+                vec![(ELLIPSIS, attrs)]
+            }
+        };
+        for (s, attrs) in spans {
+            let start = out.len();
+            out.push_str(s);
+            match out_runs.last_mut() {
+                Some((range, last)) if *last == attrs => range.end = out.len(),
+                _ => out_runs.push((start..out.len(), attrs)),
+            }
+        }
+    }
+    // Empty text still needs a section for its font.
+    if out_runs.is_empty() {
+        out_runs.push((0..0, first));
+    }
+    (out, out_runs)
+}
+
+// Split `text` into runs of identical attributes. Ranges that split a
+// char are widened to cover it.
+fn attribute_runs(
+    text: &str,
+    defaults: &Attributes,
+    ranges: &[(std::ops::Range<usize>, Attribute)],
+) -> Vec<(std::ops::Range<usize>, Attributes)> {
+    let snap = |mut i: usize, up: bool| {
+        i = i.min(text.len());
+        while !text.is_char_boundary(i) {
+            if up {
+                i += 1;
+            } else {
+                i -= 1;
+            }
+        }
+        i
+    };
+    let ranges: Vec<_> = ranges
+        .iter()
+        .map(|(r, attr)| (snap(r.start, false)..snap(r.end, true), attr))
+        .collect();
+    let mut bounds: Vec<usize> = ranges
+        .iter()
+        .flat_map(|(r, _)| [r.start, r.end])
+        .chain([0, text.len()])
+        .collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut runs: Vec<(std::ops::Range<usize>, Attributes)> = Vec::new();
+    for w in bounds.windows(2) {
+        let (start, end) = (w[0], w[1]);
+        let mut attrs = defaults.clone();
+        for (range, attr) in &ranges {
+            if range.start <= start && end <= range.end {
+                attrs.apply(attr);
+            }
+        }
+        match runs.last_mut() {
+            Some((range, last)) if *last == attrs => range.end = end,
+            _ => runs.push((start..end, attrs)),
+        }
+    }
+    // Empty text still needs a section for its font.
+    if runs.is_empty() {
+        runs.push((0..0, defaults.clone()));
+    }
+    runs
 }
 
 impl piet::TextLayoutBuilder for PietTextLayoutBuilder<'_, '_> {
@@ -704,54 +1195,260 @@ impl piet::TextLayoutBuilder for PietTextLayoutBuilder<'_, '_> {
     }
 
     fn default_attribute(mut self, attr: impl Into<piet::TextAttribute>) -> Self {
-        match attr.into() {
-            piet::TextAttribute::TextColor(color) => self.color = color,
-            piet::TextAttribute::FontFamily(font) => self.font = Some(font),
-            piet::TextAttribute::FontSize(size) => self.size = size,
-            _ => (),
-        }
+        self.defaults.set(&attr.into());
         self
     }
 
     fn range_attribute(
         self,
-        _range: impl std::ops::RangeBounds<usize>,
-        _attribute: impl Into<piet::TextAttribute>,
+        range: impl std::ops::RangeBounds<usize>,
+        attribute: impl Into<piet::TextAttribute>,
     ) -> Self {
-        todo!()
+        self.range(range, Attribute::Piet(attribute.into()))
     }
 
     // From text_system:
     fn build(self) -> Result<Self::Out, piet::Error> {
         let scale_factor = self.params.scale_factor();
 
-        let node_size = Vec2::new(self.max_width as f32, f32::MAX);
+        let runs = attribute_runs(&self.text, &self.defaults, &self.ranges);
+        let key = LayoutKey {
+            text: self.text.clone(),
+            runs: runs.iter().map(|(r, a)| (r.clone(), a.into())).collect(),
+            alignment: std::mem::discriminant(&self.alignment),
+            overflow: self.overflow,
+            tab_stops: (&self.tab_stops).into(),
+            max_width: self.max_width.to_bits(),
+            scale_factor: scale_factor.to_bits(),
+            fonts: self.params.font_registry.borrow().generation(),
+        };
+        if let Some(layout) = self.params.layout_cache.borrow_mut().get(&key) {
+            return Ok(layout);
+        }
+
+        let result = self.lay_out_overflow(runs);
+
+        // Provisional layouts are built again once their fonts load.
+        if let Some(layout) = result.as_ref().ok().filter(|l| !l.provisional) {
+            self.params
+                .layout_cache
+                .borrow_mut()
+                .insert(key, layout.clone());
+        }
+        result
+    }
+}
+
+impl PietTextLayoutBuilder<'_, '_> {
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Space added after each character, in dp. Lines are wrapped
+    /// with it.
+    pub fn letter_spacing(mut self, spacing: f64) -> Self {
+        self.defaults.letter_spacing = spacing;
+        self
+    }
+
+    pub fn line_height(mut self, height: LineHeight) -> Self {
+        self.defaults.line_height = height;
+        self
+    }
+
+    pub fn range_letter_spacing(
+        self,
+        range: impl std::ops::RangeBounds<usize>,
+        spacing: f64,
+    ) -> Self {
+        self.range(range, Attribute::LetterSpacing(spacing))
+    }
+
+    /// The height of each line is the largest of its characters'.
+    pub fn range_line_height(
+        self,
+        range: impl std::ops::RangeBounds<usize>,
+        height: LineHeight,
+    ) -> Self {
+        self.range(range, Attribute::LineHeight(height))
+    }
+
+    fn range(mut self, range: impl std::ops::RangeBounds<usize>, attribute: Attribute) -> Self {
+        let range = piet::util::resolve_range(range, self.text.len());
+        if !range.is_empty() {
+            self.ranges.push((range, attribute));
+        }
+        self
+    }
+
+    /// Where tabs stop. Lines are wrapped with tabs widened to them.
+    pub fn tab_stops(mut self, tab_stops: TabStops) -> Self {
+        self.tab_stops = tab_stops;
+        self
+    }
+
+    // Lay out the text, then again cut short if it overflows. The
+    // layout is of the text that's left, so its text, lines and hit
+    // testing match what's drawn.
+    fn lay_out_overflow(
+        &self,
+        runs: Vec<(std::ops::Range<usize>, Attributes)>,
+    ) -> Result<PietTextLayout, piet::Error> {
+        let (width, line, max_lines) = match self.overflow {
+            Overflow::Visible => return self.lay_out(&self.text, runs, self.max_width),
+            Overflow::MaxLines(n) => (self.max_width, n.max(1) - 1, n.max(1)),
+            _ => (f64::MAX, 0, 1),
+        };
+        let layout = self.lay_out(&self.text, runs.clone(), width)?;
+        let l = match layout.line_metrics.get(line) {
+            Some(l) => l,
+            None => return Ok(layout),
+        };
+        let content = l.start_offset..l.end_offset - l.trailing_whitespace;
+        let clusters = &layout.clusters;
+        let fits = layout::fit_from_start(clusters, content.clone(), self.max_width) == content.end;
+        if fits && layout.line_metrics.len() <= max_lines {
+            return Ok(layout);
+        }
+
+        // Leave room for the widest ellipsis the line could end with.
+        let mut ellipsis: f64 = 0.0;
+        let mut provisional = layout.provisional;
+        if self.overflow != Overflow::Clip {
+            for (_, attrs) in runs
+                .iter()
+                .filter(|(r, _)| r.start <= content.end && content.start <= r.end)
+            {
+                let e = self.lay_out(
+                    &ELLIPSIS.into(),
+                    vec![(0..ELLIPSIS.len(), attrs.clone())],
+                    f64::MAX,
+                )?;
+                ellipsis = ellipsis.max(e.size.width);
+                provisional |= e.provisional;
+            }
+        }
+
+        let room = self.max_width - ellipsis;
+        let text = &self.text;
+        let trim_end = |end: usize| text[..end].trim_end().len();
+        let pieces = match self.overflow {
+            Overflow::Clip => vec![Some(0..layout::fit_from_start(clusters, content, room))],
+            Overflow::EllipsisMiddle if !fits => {
+                let head = layout::fit_from_start(clusters, content.clone(), room / 2.0);
+                let tail = layout::fit_from_end(clusters, content.clone(), room / 2.0);
+                let tail = content.end - text[tail..content.end].trim_start().len();
+                vec![Some(0..trim_end(head)), None, Some(tail..content.end)]
+            }
+            _ => {
+                let end = layout::fit_from_start(clusters, content, room);
+                vec![Some(0..trim_end(end)), None]
+            }
+        };
+
+        let (text, runs) = splice(text, &runs, &pieces);
+        let mut layout = self.lay_out(&text.into(), runs, width)?;
+        layout.provisional |= provisional;
+        Ok(layout)
+    }
+
+    // Lay out `text` in runs of attributes, wrapped at `max_width`.
+    fn lay_out(
+        &self,
+        text: &Arc<str>,
+        runs: Vec<(std::ops::Range<usize>, Attributes)>,
+        max_width: f64,
+    ) -> Result<PietTextLayout, piet::Error> {
+        let scale_factor = self.params.scale_factor();
+
+        // In pixels, like the font sizes.
+        let node_size = Vec2::new((max_width * scale_factor) as f32, f32::MAX);
 
         let mut text_pipeline = self.params.text_pipeline.borrow_mut();
         let mut font_atlas_set_storage = self.params.font_atlas_set_storage.borrow_mut();
         let mut texture_atlases = self.params.texture_atlases.borrow_mut();
         let mut textures = self.params.textures.borrow_mut();
 
-        // make one text section until we do attributes
-        let alignment = convert_alignment(self.alignment);
-        let text = bevy::text::Text {
-            sections: vec![TextSection {
-                value: self.text.as_str().to_string(),
-                style: TextStyle {
-                    font: self
-                        .params
-                        .asset_server
-                        .load(self.font.expect("missing font name").name()),
-                    font_size: self.size as f32,
-                    color: convert_color(self.color),
-                },
-            }],
+        // One section per run of attributes, split again where
+        // characters need a fallback font. Glyph byte indices are
+        // relative to their section.
+        let mut fonts = self.params.fonts.borrow_mut();
+        let mut font_registry = self.params.font_registry.borrow_mut();
+        let mut sections = Vec::new();
+        let mut section_starts = Vec::new();
+        // Underlines and strikethroughs: the range, the top of the
+        // line above the baseline, its thickness and color.
+        let mut decorated = Vec::new();
+        let mut provisional = false;
+        // Where each run ends, its letter spacing and line height.
+        let spacing: Vec<_> = runs
+            .iter()
+            .map(|(range, attrs)| (range.end, attrs.letter_spacing, attrs.line_height()))
+            .collect();
+        let spacing_at =
+            |i: usize| spacing[spacing.partition_point(|s| s.0 <= i).min(spacing.len() - 1)];
+        for (range, attrs) in runs {
+            // System fonts are loaded on first use.
+            let mut font = match &attrs.font {
+                Some(family) => {
+                    font_registry.load(&mut fonts, family.name(), attrs.weight, attrs.style)?
+                }
+                None => font_registry.default_font(&mut fonts)?,
+            };
+            // Fonts from the asset server load asynchronously. Lay
+            // out with a stand-in until they're ready.
+            if fonts.get(&font).is_none() {
+                if !font_registry.has_failed(&font) {
+                    font_registry.wait_for(&font);
+                    provisional = true;
+                }
+                font = font_registry.stand_in(&mut fonts, attrs.weight, attrs.style)?;
+            }
+            let metrics = font_registry.decorations(&font);
+            if attrs.underline {
+                decorated.push((
+                    range.clone(),
+                    (metrics.underline_position as f64) * attrs.size,
+                    (metrics.underline_thickness as f64) * attrs.size,
+                    attrs.color,
+                ));
+            }
+            if attrs.strikethrough {
+                decorated.push((
+                    range.clone(),
+                    (metrics.strikethrough_position as f64) * attrs.size,
+                    (metrics.strikethrough_thickness as f64) * attrs.size,
+                    attrs.color,
+                ));
+            }
+            let text = &text[range.clone()];
+            for (sub, font) in
+                font_registry.fallback_runs(&mut fonts, text, font, attrs.weight, attrs.style)
+            {
+                section_starts.push(range.start + sub.start);
+                sections.push(TextSection {
+                    // Fonts draw a box or nothing for tabs. They're
+                    // widened to their stops when placed.
+                    value: text[sub].replace('\t', " "),
+                    style: TextStyle {
+                        font,
+                        font_size: attrs.size as f32,
+                        color: convert_color(attrs.color),
+                    },
+                });
+            }
+        }
+
+        let alignment = convert_alignment();
+        let bevy_text = bevy::text::Text {
+            sections,
             alignment,
         };
 
         // We only need the section colors from this struct to render.
         let render_text = bevy::text::Text {
-            sections: text
+            sections: bevy_text
                 .sections
                 .iter()
                 .map(|s| TextSection {
@@ -766,8 +1463,8 @@ impl piet::TextLayoutBuilder for PietTextLayoutBuilder<'_, '_> {
         };
 
         match text_pipeline.queue_text(
-            &self.params.fonts,
-            &text.sections,
+            &fonts,
+            &bevy_text.sections,
             scale_factor,
             alignment,
             node_size,
@@ -775,87 +1472,136 @@ impl piet::TextLayoutBuilder for PietTextLayoutBuilder<'_, '_> {
             &mut texture_atlases,
             &mut textures,
         ) {
-            Ok(text_layout_info) => {
+            Ok((mut text_layout_info, placed)) => {
                 // Font metrics will be in pixel values, but we want
                 // dp for Piet.
                 let inv_scale = (1.0 / scale_factor) as f32;
                 let size = text_layout_info.size * inv_scale;
-                let size = kurbo::Size::new(size.x as f64, size.y as f64);
-                let glyphs: Vec<_> = text_layout_info
+                let mut size = kurbo::Size::new(size.x as f64, size.y as f64);
+                let mut glyphs: Vec<_> = text_layout_info
                     .glyphs
                     .iter()
                     .cloned()
                     .map(|mut g| {
                         g.position *= inv_scale;
                         g.size *= inv_scale;
+                        // Offsets into the whole text.
+                        g.byte_index += section_starts[g.section_index];
                         g
                     })
                     .collect();
 
-                // Unfortunately we don't have access to line metrics
-                // internal to glyph_brush_layout, so we have to
-                // recreate them here. They may be inaccurate. In
-                // addition, the lines heuristic may be inaccurate
-                // with respect to the internal line breaker.
-                let section_fonts = &text
+                let section_fonts = &bevy_text
                     .sections
                     .iter()
+                    //.unique() - probably dupes, but font_size isn't
+                    // hashable; we need to maintain section indices
+                    // as well
                     .map(|s| {
                         let TextStyle {
                             font, font_size, ..
                         } = &s.style;
-                        // fonts are already checked above
-                        (self.params.fonts.get(font).unwrap(), font_size)
+                        fonts
+                            .get(font)
+                            .map(|font| ab_glyph::Font::as_scaled(&font.font, *font_size))
+                            .ok_or(piet::Error::MissingFont)
                     })
-                    //.unique() - probably dupes, but font_size isn't
-                    // hashable; we need to maintain section indices
-                    // as well
-                    .map(|(font, size)| ab_glyph::Font::as_scaled(&font.font, *size))
-                    .collect::<Vec<_>>();
+                    .collect::<Result<Vec<_>, _>>()?;
+                let section_at = |i: usize| section_starts.partition_point(|&s| s <= i) - 1;
+
+                let mut advances = vec![0.0; text.len()];
+                let mut prev: Option<(usize, usize, ab_glyph::GlyphId)> = None;
+                for (i, c) in text.char_indices() {
+                    let section = section_at(i);
+                    let font = section_fonts[section];
+                    let id = font.glyph_id(if c == '\t' { ' ' } else { c });
+                    if let Some((p, p_section, p_id)) = prev {
+                        if p_section == section {
+                            advances[p] += font.kern(p_id, id) as f64;
+                        }
+                    }
+                    advances[i] = font.h_advance(id) as f64;
+                    prev = Some((i, section, id));
+                }
 
-                let mut y_offset = 0.0;
-                let mut line_metrics = Vec::new();
-                for (start, end) in lines(&glyphs).into_iter() {
-                    let (ascent, _descent, height, line_gap) = &glyphs[start..end]
+                // Where glyph_brush_layout broke the lines, from every
+                // glyph it placed, and again where letter spacing and
+                // tabs make them wider than `max_width`.
+                let lines = layout::line_ranges(
+                    text,
+                    placed
                         .iter()
-                        .map(|g| {
-                            let f = section_fonts[g.section_index];
-                            (
-                                f.ascent() as f64,
-                                f.descent() as f64,
-                                f.height() as f64,
-                                f.line_gap() as f64,
-                            )
-                        })
-                        .reduce(|m1, m2| {
-                            (
-                                m1.0.max(m2.0),
-                                m1.1.max(m2.1),
-                                m1.2.max(m2.2),
-                                m1.3.max(m2.3),
-                            )
-                        })
-                        // FIX: a line could be empty of glyphs (only
-                        // whitespace) where this will fail
-                        .unwrap();
-                    // see: https://docs.rs/piet/latest/piet/struct.LineMetric.html
-                    line_metrics.push(piet::LineMetric {
-                        // These offsets only work because we only
-                        // have one section. FIX:
-                        start_offset: glyphs[start].byte_index,
-                        // 1) This does not include trailing
-                        // whitespace since we're building off
-                        // glyphs. 2) This may be wrong w/ respect to
-                        // utf8. FIX:
-                        end_offset: glyphs[end - 1].byte_index + 1,
-                        // TODO:
-                        trailing_whitespace: 0,
-                        baseline: *ascent,
-                        height: *height,
-                        y_offset,
+                        .map(|&(section, i, _, y)| (section_starts[section] + i, y)),
+                );
+                let lines =
+                    layout::wrap_lines(text, &lines, &advances, max_width, &self.tab_stops, |i| {
+                        spacing_at(i).1
                     });
-                    y_offset += *height + *line_gap;
+                let line_metrics = layout::line_metrics(
+                    text,
+                    &lines,
+                    |i| {
+                        let f = section_fonts[section_at(i)];
+                        (f.ascent() as f64, f.descent() as f64, f.line_gap() as f64)
+                    },
+                    |i| spacing_at(i).2,
+                );
+
+                // Lines are laid out left to right in logical order.
+                // Reorder and align them here.
+                let placement = layout::place_lines(
+                    text,
+                    &lines,
+                    &advances,
+                    self.alignment,
+                    max_width,
+                    &self.tab_stops,
+                    |i| spacing_at(i).1,
+                );
+
+                // glyph_brush_layout spaces lines by the fonts' own
+                // line heights. Move each glyph with its character,
+                // onto its line's baseline.
+                let height = line_metrics.last().map_or(0.0, |l| l.y_offset + l.height);
+                let mut origins = vec![None; text.len()];
+                for &(section, i, x, baseline) in &placed {
+                    origins[section_starts[section] + i] =
+                        Some(((x * inv_scale) as f64, (baseline * inv_scale) as f64));
+                }
+                let shifts =
+                    layout::cluster_shifts(&placement.clusters, &line_metrics, height, &origins);
+
+                for (g, info) in glyphs.iter_mut().zip(&mut text_layout_info.glyphs) {
+                    if let Some(i) = layout::cluster_index(&placement.clusters, g.byte_index) {
+                        let (dx, dy) = shifts[i];
+                        let shift = Vec2::new(dx as f32, dy as f32);
+                        g.position += shift;
+                        info.position += shift * scale_factor as f32;
+                    }
                 }
+                size.width = placement.width;
+                size.height = height;
+
+                // Decorations run under each line's text, but not its
+                // trailing whitespace.
+                let decorations: Vec<_> = decorated
+                    .iter()
+                    .flat_map(|(range, position, thickness, color)| {
+                        line_metrics.iter().flat_map(|l| {
+                            let end = l.end_offset - l.trailing_whitespace;
+                            let top = l.y_offset + l.baseline - position;
+                            layout::spans(
+                                &placement.clusters,
+                                range.start.max(l.start_offset)..range.end.min(end),
+                            )
+                            .into_iter()
+                            .filter(|&(_, x0, x1)| x1 > x0)
+                            .map(move |(_, x0, x1)| {
+                                (kurbo::Rect::new(x0, top, x1, top + thickness), *color)
+                            })
+                        })
+                    })
+                    .collect();
 
                 let image_bounds = glyphs
                     .iter()
@@ -871,50 +1617,27 @@ impl piet::TextLayoutBuilder for PietTextLayoutBuilder<'_, '_> {
                 let text_layout_info = Arc::new(text_layout_info);
 
                 Ok(PietTextLayout {
-                    text: self.text.clone(),
+                    text: text.clone(),
                     glyphs: glyphs.into(),
                     text_layout_info,
                     render_text: render_text.into(),
                     size,
                     line_metrics: line_metrics.into(),
                     image_bounds,
+                    clusters: placement.clusters.into(),
+                    decorations: decorations.into(),
+                    line_starts: placement.line_starts.into(),
+                    trailing_whitespace_width: placement.trailing_whitespace_width,
+                    provisional,
                 })
             }
-            Err(TextError::NoSuchFont) => {
-                // font asset not loaded yet - what if the asset fails
-                // to load?
-                Err(piet::Error::MissingFont)
-            }
+            // Every section's font is loaded above.
+            Err(TextError::NoSuchFont) => Err(piet::Error::MissingFont),
             Err(e) => Err(piet::Error::BackendError(e.into())),
         }
     }
 }
 
-// Start a new line for any glyph where the glyph's x position is less
-// than the previous glyph's.
-fn lines(glyphs: &Vec<PositionedGlyph>) -> Vec<(usize, usize)> {
-    let mut lines = Vec::new();
-    let mut start = 0;
-    let mut last_x = -f32::INFINITY;
-
-    for (i, glyph) in glyphs.iter().enumerate() {
-        let x = glyph.position.x;
-        if x < last_x {
-            // new line
-            lines.push((start, i));
-            start = i;
-        }
-        last_x = x;
-    }
-
-    // last line
-    if glyphs.len() > 0 {
-        lines.push((start, glyphs.len()));
-    }
-
-    lines
-}
-
 // Write a system to cache size here? Trigger relayout when an image
 // loads if size() initially returns empty?
 #[derive(Clone, Debug)]
@@ -946,6 +1669,7 @@ pub struct NodeBundle {
     pub global_transform: GlobalTransform,
     pub visibility: Visibility,
     pub computed_visibility: ComputedVisibility,
+    pub piet_node: PietNode,
 }
 
 // is this needed? what about ImageMode and CalculatedSize?
@@ -959,14 +1683,42 @@ pub struct TextBundle {
     pub global_transform: GlobalTransform,
     pub visibility: Visibility,
     pub computed_visibility: ComputedVisibility,
+    pub piet_node: PietNode,
 }
 
-#[derive(Default)]
-pub struct PietPlugin;
+pub struct PietPlugin {
+    /// Find fonts in the system font directories on startup.
+    pub system_fonts: bool,
+}
+
+impl Default for PietPlugin {
+    fn default() -> Self {
+        Self { system_fonts: true }
+    }
+}
+
+impl PietPlugin {
+    /// Everything but rendering: the resources [`PietParams`] needs
+    /// besides bevy's text assets, and the systems that keep them up
+    /// to date. For apps that draw without a window.
+    pub fn headless(&self, app: &mut App) {
+        app.init_resource::<FontRegistry>()
+            .init_resource::<TextLayoutCache>()
+            .init_resource::<PietTextPipeline>()
+            .init_resource::<CanvasDepths>()
+            .add_event::<FontEvent>()
+            .add_system(watch_fonts)
+            .add_system_to_stage(CoreStage::Last, age_text_layouts);
+        if self.system_fonts {
+            app.world.resource_mut::<FontRegistry>().add_system_fonts();
+        }
+    }
+}
 
 // This is pared down from UiPlugin.
 impl Plugin for PietPlugin {
     fn build(&self, app: &mut App) {
+        self.headless(app);
         app.add_plugin(ExtractComponentPlugin::<UiCameraConfig>::default())
             .register_type::<Node>()
             .register_type::<UiColor>()
@@ -978,9 +1730,182 @@ impl Plugin for PietPlugin {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         let result = 2 + 2;
         assert_eq!(result, 4);
     }
+
+    #[test]
+    fn max_depth_below_next_canvas() {
+        assert_eq!(max_depth(0.0, [0.0].into_iter()), u32::MAX);
+        assert_eq!(max_depth(0.0, [0.0, 2.0, 1.0].into_iter()), 9999);
+        let z = 1.0 + max_depth(1.0, [1.0, 2.0].into_iter()) as f32 * Z_STEP;
+        assert!(z < 2.0);
+    }
+
+    #[test]
+    fn attribute_runs_split_and_merge() {
+        let defaults = Attributes::default();
+        let ranges = vec![
+            (
+                0..5,
+                Attribute::Piet(piet::TextAttribute::TextColor(piet::Color::RED)),
+            ),
+            (3..8, Attribute::Piet(piet::TextAttribute::FontSize(20.0))),
+            // Same as the default; merges with its neighbor.
+            (
+                8..10,
+                Attribute::Piet(piet::TextAttribute::FontSize(defaults.size)),
+            ),
+            // Inside "é"; widened to all of it.
+            (11..12, Attribute::Piet(piet::TextAttribute::FontSize(30.0))),
+        ];
+        let runs = attribute_runs("hello worldé", &defaults, &ranges);
+        let ranges: Vec<_> = runs.iter().map(|(r, _)| r.clone()).collect();
+        assert_eq!(ranges, vec![0..3, 3..5, 5..8, 8..11, 11..13]);
+        assert_eq!(runs[1].1.color, piet::Color::RED);
+        assert_eq!(runs[1].1.size, 20.0);
+        assert_eq!(runs[2].1.color, defaults.color);
+        assert_eq!(runs[3].1, defaults);
+        assert_eq!(runs[4].1.size, 30.0);
+
+        // Ending inside "é" still covers it.
+        let red = [(
+            0..2,
+            Attribute::Piet(piet::TextAttribute::TextColor(piet::Color::RED)),
+        )];
+        let runs = attribute_runs("aé", &defaults, &red);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].0, 0..3);
+        assert_eq!(runs[0].1.color, piet::Color::RED);
+
+        assert_eq!(attribute_runs("", &defaults, &[]).len(), 1);
+    }
+
+    #[test]
+    fn splice_keeps_attributes() {
+        let defaults = Attributes::default();
+        let red = Attributes {
+            color: piet::Color::RED,
+            ..defaults.clone()
+        };
+        let runs = vec![(0..3, defaults.clone()), (3..6, red.clone())];
+        let (text, spliced) = splice("abcdef", &runs, &[Some(0..2), None, Some(4..6)]);
+        assert_eq!(text, "ab\u{2026}ef");
+        assert_eq!(spliced, vec![(0..5, defaults.clone()), (5..7, red.clone())]);
+
+        let (text, spliced) = splice("abcdef", &runs, &[Some(0..4), None]);
+        assert_eq!(text, "abcd\u{2026}");
+        assert_eq!(spliced, vec![(0..3, defaults.clone()), (3..7, red)]);
+
+        let (text, spliced) = splice("abcdef", &runs, &[Some(0..0)]);
+        assert_eq!(text, "");
+        assert_eq!(spliced, vec![(0..0, defaults)]);
+    }
+
+    // Laid out at glyph_brush_layout's breaks, with every character 10
+    // wide and lines 12 high, 10 of that above the baseline, and 13
+    // apart.
+    fn laid_out(text: &str, wrapped: &[(usize, f32)]) -> PietTextLayout {
+        let lines = layout::line_ranges(text, wrapped.iter().copied());
+        let line_metrics = layout::line_metrics(text, &lines, |_| (10.0, -2.0, 1.0), |_| None);
+        let advances = vec![10.0; text.len()];
+        let placement = layout::place_lines(
+            text,
+            &lines,
+            &advances,
+            piet::TextAlignment::Start,
+            f64::MAX,
+            &TabStops::default(),
+            |_| 0.0,
+        );
+        let height = line_metrics.last().map_or(0.0, |l| l.y_offset + l.height);
+        PietTextLayout {
+            size: kurbo::Size::new(placement.width, height),
+            line_metrics: line_metrics.into(),
+            clusters: placement.clusters.into(),
+            line_starts: placement.line_starts.into(),
+            trailing_whitespace_width: placement.trailing_whitespace_width,
+            ..PietTextLayout::empty(text)
+        }
+    }
+
+    #[test]
+    fn origin_in_rect() {
+        let layout = laid_out("ab\ncd", &[]);
+        assert_eq!(layout.size, kurbo::Size::new(20.0, 25.0));
+        let rect = kurbo::Rect::new(5.0, 100.0, 105.0, 200.0);
+
+        let top = layout.origin_in(rect, VerticalAlignment::Top);
+        assert_eq!(top, kurbo::Point::new(5.0, 100.0));
+        let center = layout.origin_in(rect, VerticalAlignment::Center);
+        assert_eq!(center, kurbo::Point::new(5.0, 137.5));
+        assert_eq!(center.y + layout.size.height * 0.5, rect.center().y);
+        // The first line's baseline sits on the bottom of the rect.
+        let baseline = layout.origin_in(rect, VerticalAlignment::Baseline);
+        assert_eq!(baseline, kurbo::Point::new(5.0, 190.0));
+        let l = piet::TextLayout::line_metric(&layout, 0).unwrap();
+        assert_eq!(baseline.y + l.y_offset + l.baseline, rect.y1);
+
+        // Empty text is one line high.
+        let layout = laid_out("", &[]);
+        assert_eq!(layout.size.height, 12.0);
+        let baseline = layout.origin_in(rect, VerticalAlignment::Baseline);
+        assert_eq!(baseline.y, 190.0);
+    }
+
+    #[test]
+    fn selection_rects() {
+        use piet::TextLayout;
+
+        let layout = laid_out("ab cd\nef", &[]);
+        // Inside one line, down to the next.
+        assert_eq!(
+            layout.rects_for_range(1..4),
+            vec![kurbo::Rect::new(10.0, 0.0, 40.0, 13.0)]
+        );
+        // Across lines, contiguous.
+        assert_eq!(
+            layout.rects_for_range(3..8),
+            vec![
+                kurbo::Rect::new(30.0, 0.0, 50.0, 13.0),
+                kurbo::Rect::new(0.0, 13.0, 20.0, 25.0)
+            ]
+        );
+
+        // Wrapped after the spaces, which run past the 40 wide "cdef".
+        let wrapped: Vec<_> = (0..9)
+            .map(|i| (i, if i < 5 { 10.0 } else { 20.0 }))
+            .collect();
+        let layout = laid_out("ab   cdef", &wrapped);
+        assert_eq!(layout.size.width, 40.0);
+        assert_eq!(
+            layout.rects_for_range(0..5),
+            vec![kurbo::Rect::new(0.0, 0.0, 40.0, 13.0)]
+        );
+
+        // The line break on the shorter first line.
+        let layout = laid_out("ab\ncdef", &[]);
+        assert!(layout.rects_for_range(2..3).is_empty());
+        assert_eq!(
+            layout.rects_for_range(1..4),
+            vec![
+                kurbo::Rect::new(10.0, 0.0, 20.0, 13.0),
+                kurbo::Rect::new(0.0, 13.0, 10.0, 25.0)
+            ]
+        );
+
+        // "b אב" is split in two by the reversed word.
+        let layout = laid_out("ab אב cd", &[]);
+        assert_eq!(
+            layout.rects_for_range(1..5),
+            vec![
+                kurbo::Rect::new(10.0, 0.0, 30.0, 12.0),
+                kurbo::Rect::new(40.0, 0.0, 50.0, 12.0)
+            ]
+        );
+    }
 }
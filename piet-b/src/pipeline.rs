@@ -0,0 +1,118 @@
+//! Text layout with glyph_brush_layout. This is bevy's `TextPipeline`,
+//! but it also returns the glyphs the pipeline drops, so line breaks
+//! are found without laying text out twice.
+
+use bevy::{
+    asset::HandleId,
+    math::Vec2,
+    prelude::{Assets, Handle, Image as BevyImage, TextureAtlas},
+    text::{Font, FontAtlasSet, GlyphBrush, TextAlignment, TextError, TextLayoutInfo, TextSection},
+    utils::HashMap,
+};
+use glyph_brush_layout::{
+    ab_glyph::{self, PxScale, ScaleFont},
+    FontId, SectionText,
+};
+
+#[derive(Default)]
+pub struct PietTextPipeline {
+    brush: GlyphBrush,
+    font_ids: HashMap<HandleId, FontId>,
+}
+
+impl PietTextPipeline {
+    fn font_id(&mut self, handle: &Handle<Font>, font: &Font) -> FontId {
+        let brush = &mut self.brush;
+        *self
+            .font_ids
+            .entry(handle.id)
+            .or_insert_with(|| brush.add_font(handle.clone(), font.font.clone()))
+    }
+
+    /// Lay `sections` out and add their glyphs to the font atlases.
+    /// Also returns the section, byte index in that section, left edge
+    /// and baseline of every glyph glyph_brush_layout placed,
+    /// whitespace included. Edges are from the left of the layout and
+    /// baselines heights above its bottom, like the positions of the
+    /// `TextLayoutInfo` glyphs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn queue_text(
+        &mut self,
+        fonts: &Assets<Font>,
+        sections: &[TextSection],
+        scale_factor: f64,
+        alignment: TextAlignment,
+        bounds: Vec2,
+        font_atlas_set_storage: &mut Assets<FontAtlasSet>,
+        texture_atlases: &mut Assets<TextureAtlas>,
+        textures: &mut Assets<BevyImage>,
+    ) -> Result<(TextLayoutInfo, Vec<(usize, usize, f32, f32)>), TextError> {
+        let mut scaled_fonts = Vec::new();
+        let sections = sections
+            .iter()
+            .map(|section| {
+                let font = fonts
+                    .get(&section.style.font)
+                    .ok_or(TextError::NoSuchFont)?;
+                let font_id = self.font_id(&section.style.font, font);
+                let font_size = section.style.font_size * scale_factor as f32;
+                scaled_fonts.push(ab_glyph::Font::as_scaled(&font.font, font_size));
+                Ok(SectionText {
+                    font_id,
+                    scale: PxScale::from(font_size),
+                    text: &section.value,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let section_glyphs = self.brush.compute_glyphs(&sections, bounds, alignment)?;
+        if section_glyphs.is_empty() {
+            let info = TextLayoutInfo {
+                glyphs: Vec::new(),
+                size: Vec2::ZERO,
+            };
+            return Ok((info, Vec::new()));
+        }
+
+        let mut min = Vec2::splat(f32::MAX);
+        let mut max = Vec2::splat(f32::MIN);
+        for sg in &section_glyphs {
+            let scaled_font = scaled_fonts[sg.section_index];
+            let glyph = &sg.glyph;
+            min.x = min.x.min(glyph.position.x);
+            min.y = min.y.min(glyph.position.y - scaled_font.ascent());
+            max.x = max
+                .x
+                .max(glyph.position.x + scaled_font.h_advance(glyph.id));
+            max.y = max.y.max(glyph.position.y - scaled_font.descent());
+        }
+        // `process_glyphs` measures right and up from here.
+        let (left, bottom) = (min.x.floor(), max.y.floor());
+        let placed = section_glyphs
+            .iter()
+            .map(|g| {
+                let position = g.glyph.position;
+                (
+                    g.section_index,
+                    g.byte_index,
+                    position.x - left,
+                    bottom - position.y,
+                )
+            })
+            .collect();
+
+        let glyphs = self.brush.process_glyphs(
+            section_glyphs,
+            &sections,
+            font_atlas_set_storage,
+            fonts,
+            texture_atlases,
+            textures,
+        )?;
+        let info = TextLayoutInfo {
+            glyphs,
+            size: max - min,
+        };
+        Ok((info, placed))
+    }
+}
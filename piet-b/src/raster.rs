@@ -0,0 +1,525 @@
+//! A software rasterizer for the nodes a [`Piet`](crate::Piet)
+//! spawns. It follows the UI render pipeline (quads, clips, glyph
+//! atlases, depth order) so output can be checked without a GPU.
+
+use crate::{kurbo, CanvasId, PietNode};
+use bevy::{
+    ecs::system::{SystemParam, SystemState},
+    math::{Mat4, Vec2, Vec3, Vec4},
+    prelude::{Assets, Image as BevyImage, Query, Res, TextureAtlas, Transform, World},
+    render::render_resource::TextureFormat,
+    sprite::Rect,
+    text::TextLayoutInfo,
+    ui::{CalculatedClip, Node, UiColor, UiImage},
+};
+use std::path::Path;
+
+pub type RasterQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static PietNode,
+        &'static Node,
+        // Piet nodes have no parents, so this is the same as
+        // GlobalTransform, which may not be propagated headless.
+        &'static Transform,
+        Option<&'static UiColor>,
+        Option<&'static UiImage>,
+        Option<&'static bevy::text::Text>,
+        Option<&'static TextLayoutInfo>,
+        Option<&'static CalculatedClip>,
+    ),
+>;
+
+#[derive(SystemParam)]
+pub struct RasterParams<'w, 's> {
+    pub nodes: RasterQuery<'w, 's>,
+    pub images: Res<'w, Assets<BevyImage>>,
+    pub texture_atlases: Res<'w, Assets<TextureAtlas>>,
+}
+
+/// Rasterize `canvas` from `world`. Commands from the `Piet` that
+/// drew it must be applied first.
+pub fn rasterize(
+    world: &mut World,
+    canvas: CanvasId,
+    size: kurbo::Size,
+    scale_factor: f64,
+) -> Raster {
+    let mut state = SystemState::<RasterParams>::new(world);
+    let params = state.get_mut(world);
+    params.rasterize(canvas, size, scale_factor)
+}
+
+impl<'w, 's> RasterParams<'w, 's> {
+    /// `size` is the size of the window in dp. The output is in
+    /// physical pixels.
+    pub fn rasterize(&self, canvas: CanvasId, size: kurbo::Size, scale_factor: f64) -> Raster {
+        let mut target = Target::new(size, scale_factor as f32);
+
+        let mut nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(node, ..)| node.canvas == canvas)
+            .collect();
+        // The render pipeline sorts by depth; the sort is stable so
+        // ties are drawn in query order there too.
+        nodes.sort_by(|a, b| a.2.translation.z.total_cmp(&b.2.translation.z));
+
+        for (_, node, transform, color, image, text, text_layout_info, clip) in nodes {
+            if node.size == Vec2::ZERO {
+                continue;
+            }
+            let clip = clip.map(|c| c.clip);
+
+            match (text, text_layout_info) {
+                (Some(text), Some(info)) => {
+                    self.draw_glyphs(&mut target, node, transform, text, info, clip)
+                }
+                _ => {
+                    let color =
+                        Vec4::from(color.map(|c| c.0).unwrap_or_default().as_linear_rgba_f32());
+                    let image = image.and_then(|i| self.images.get(&i.0));
+                    target.draw_quad(
+                        transform.compute_matrix(),
+                        node.size,
+                        clip,
+                        |uv| match image {
+                            Some(image) => color * sample_bilinear(image, uv),
+                            // The default image is plain white.
+                            None => color,
+                        },
+                    );
+                }
+            }
+        }
+
+        target.into_raster()
+    }
+
+    fn draw_glyphs(
+        &self,
+        target: &mut Target,
+        node: &Node,
+        transform: &Transform,
+        text: &bevy::text::Text,
+        info: &TextLayoutInfo,
+        clip: Option<Rect>,
+    ) {
+        let scale_factor = target.scale_factor;
+        let alignment_offset = (node.size / -2.0).extend(0.0);
+
+        for glyph in info.glyphs.iter() {
+            let atlas = match self.texture_atlases.get(&glyph.atlas_info.texture_atlas) {
+                Some(atlas) => atlas,
+                None => continue,
+            };
+            let texture = match self.images.get(&atlas.texture) {
+                Some(texture) => texture,
+                None => continue,
+            };
+            let rect = atlas.textures[glyph.atlas_info.glyph_index];
+            let color = text
+                .sections
+                .get(glyph.section_index)
+                .map(|s| Vec4::from(s.style.color.as_linear_rgba_f32()))
+                .unwrap_or(Vec4::ONE);
+
+            // Glyph positions are in physical pixels.
+            let matrix = Mat4::from_rotation_translation(transform.rotation, transform.translation)
+                * Mat4::from_scale(transform.scale / scale_factor)
+                * Mat4::from_translation(
+                    alignment_offset * scale_factor + glyph.position.extend(0.),
+                );
+
+            target.draw_quad(matrix, rect.size(), clip, |uv| {
+                let texel = rect.min + uv * rect.size();
+                color * texel_at(texture, texel.x as u32, texel.y as u32)
+            });
+        }
+    }
+}
+
+/// An RGBA image with 8 bits per channel, sRGB encoded with straight
+/// alpha.
+#[derive(Clone, Debug)]
+pub struct Raster {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Raster {
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = (y * self.width + x) as usize * 4;
+        [
+            self.data[i],
+            self.data[i + 1],
+            self.data[i + 2],
+            self.data[i + 3],
+        ]
+    }
+
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<(), piet::Error> {
+        let file = std::fs::File::create(path).map_err(|e| piet::Error::BackendError(e.into()))?;
+        let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&self.data))
+            .map_err(|e| piet::Error::BackendError(e.into()))
+    }
+}
+
+// Linear, premultiplied accumulation buffer.
+struct Target {
+    width: u32,
+    height: u32,
+    // Window height in dp, for flipping to y-down.
+    window_height: f32,
+    scale_factor: f32,
+    pixels: Vec<Vec4>,
+}
+
+impl Target {
+    fn new(size: kurbo::Size, scale_factor: f32) -> Self {
+        let width = (size.width as f32 * scale_factor).ceil() as u32;
+        let height = (size.height as f32 * scale_factor).ceil() as u32;
+        Self {
+            width,
+            height,
+            window_height: size.height as f32,
+            scale_factor,
+            pixels: vec![Vec4::ZERO; (width * height) as usize],
+        }
+    }
+
+    // Pixel centers in UI space (dp, y-up).
+    fn to_ui(&self, x: u32, y: u32) -> Vec2 {
+        Vec2::new(
+            (x as f32 + 0.5) / self.scale_factor,
+            self.window_height - (y as f32 + 0.5) / self.scale_factor,
+        )
+    }
+
+    /// Draw a quad of `size` centered on the origin of `matrix`.
+    /// `shade` takes texture coordinates (y-down) and returns a
+    /// linear, straight alpha color.
+    fn draw_quad(
+        &mut self,
+        matrix: Mat4,
+        size: Vec2,
+        clip: Option<Rect>,
+        shade: impl Fn(Vec2) -> Vec4,
+    ) {
+        let half = size / 2.0;
+        let corners = [
+            Vec3::new(-half.x, -half.y, 0.),
+            Vec3::new(half.x, -half.y, 0.),
+            Vec3::new(half.x, half.y, 0.),
+            Vec3::new(-half.x, half.y, 0.),
+        ]
+        .map(|c| matrix.transform_point3(c).truncate());
+        let mut min = corners.iter().fold(Vec2::splat(f32::MAX), |a, c| a.min(*c));
+        let mut max = corners.iter().fold(Vec2::splat(f32::MIN), |a, c| a.max(*c));
+        if let Some(clip) = clip {
+            min = min.max(clip.min);
+            max = max.min(clip.max);
+        }
+        if min.x >= max.x || min.y >= max.y {
+            return;
+        }
+
+        // UI space to pixel bounds, flipping y.
+        let x0 = (min.x * self.scale_factor).floor().max(0.0) as u32;
+        let x1 = ((max.x * self.scale_factor).ceil() as u32).min(self.width);
+        let y0 = ((self.window_height - max.y) * self.scale_factor)
+            .floor()
+            .max(0.0) as u32;
+        let y1 =
+            (((self.window_height - min.y) * self.scale_factor).ceil() as u32).min(self.height);
+
+        let inverse = matrix.inverse();
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let p = self.to_ui(x, y);
+                if p.x < min.x || p.x > max.x || p.y < min.y || p.y > max.y {
+                    continue;
+                }
+                let local = inverse.transform_point3(p.extend(0.)).truncate();
+                if local.x.abs() > half.x || local.y.abs() > half.y {
+                    continue;
+                }
+                let uv = Vec2::new(local.x / size.x + 0.5, 0.5 - local.y / size.y);
+                let src = shade(uv);
+                let dst = &mut self.pixels[(y * self.width + x) as usize];
+                let a = src.w;
+                *dst = (src.truncate() * a).extend(a) + *dst * (1.0 - a);
+            }
+        }
+    }
+
+    fn into_raster(self) -> Raster {
+        let data = self
+            .pixels
+            .iter()
+            .flat_map(|p| {
+                let a = p.w;
+                let rgb = if a > 0.0 {
+                    p.truncate() / a
+                } else {
+                    Vec3::ZERO
+                };
+                [
+                    linear_to_srgb(rgb.x),
+                    linear_to_srgb(rgb.y),
+                    linear_to_srgb(rgb.z),
+                    (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]
+            })
+            .collect();
+        Raster {
+            width: self.width,
+            height: self.height,
+            data,
+        }
+    }
+}
+
+fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+// Linear, straight alpha. Unknown formats are white.
+fn texel_at(image: &BevyImage, x: u32, y: u32) -> Vec4 {
+    let size = image.texture_descriptor.size;
+    let (width, height) = (size.width, size.height);
+    if width == 0 || height == 0 {
+        return Vec4::ONE;
+    }
+    let i = (y.min(height - 1) * width + x.min(width - 1)) as usize;
+    let unorm = |c: u8| c as f32 / 255.0;
+    match image.texture_descriptor.format {
+        TextureFormat::Rgba8UnormSrgb => {
+            let p = &image.data[i * 4..i * 4 + 4];
+            Vec4::new(
+                srgb_to_linear(p[0]),
+                srgb_to_linear(p[1]),
+                srgb_to_linear(p[2]),
+                unorm(p[3]),
+            )
+        }
+        TextureFormat::Bgra8UnormSrgb => {
+            let p = &image.data[i * 4..i * 4 + 4];
+            Vec4::new(
+                srgb_to_linear(p[2]),
+                srgb_to_linear(p[1]),
+                srgb_to_linear(p[0]),
+                unorm(p[3]),
+            )
+        }
+        TextureFormat::Rgba8Unorm => {
+            let p = &image.data[i * 4..i * 4 + 4];
+            Vec4::new(unorm(p[0]), unorm(p[1]), unorm(p[2]), unorm(p[3]))
+        }
+        TextureFormat::R8Unorm => {
+            let v = unorm(image.data[i]);
+            Vec4::new(v, v, v, 1.0)
+        }
+        _ => Vec4::ONE,
+    }
+}
+
+fn sample_bilinear(image: &BevyImage, uv: Vec2) -> Vec4 {
+    let size = image.size();
+    let p = uv * size - Vec2::splat(0.5);
+    let p0 = p.floor();
+    let t = p - p0;
+    let (x0, y0) = (p0.x.max(0.0) as u32, p0.y.max(0.0) as u32);
+    let (x1, y1) = ((p0.x + 1.0).max(0.0) as u32, (p0.y + 1.0).max(0.0) as u32);
+    let top = texel_at(image, x0, y0).lerp(texel_at(image, x1, y0), t.x);
+    let bottom = texel_at(image, x0, y1).lerp(texel_at(image, x1, y1), t.x);
+    top.lerp(bottom, t.y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::{
+        asset::AddAsset,
+        prelude::{App, Color, MinimalPlugins},
+        render::render_resource::{Extent3d, TextureDimension},
+        text::{GlyphAtlasInfo, PositionedGlyph, TextSection, TextStyle},
+    };
+
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(bevy::asset::AssetPlugin)
+            .add_asset::<BevyImage>()
+            .add_asset::<TextureAtlas>();
+        app
+    }
+
+    // A node centered on `center`, in dp with y up.
+    fn quad(app: &mut App, center: Vec2, size: Vec2, z: f32, color: Color) {
+        app.world.spawn().insert_bundle((
+            PietNode::default(),
+            Node { size },
+            Transform::from_translation(center.extend(z)),
+            UiColor(color),
+        ));
+    }
+
+    fn rasterize(app: &mut App) -> Raster {
+        super::rasterize(
+            &mut app.world,
+            CanvasId::default(),
+            kurbo::Size::new(8.0, 8.0),
+            1.0,
+        )
+    }
+
+    #[test]
+    fn fill() {
+        let mut app = app();
+        quad(
+            &mut app,
+            Vec2::new(4.0, 6.0),
+            Vec2::new(4.0, 2.0),
+            0.0,
+            Color::RED,
+        );
+        let raster = rasterize(&mut app);
+        assert_eq!((raster.width, raster.height), (8, 8));
+        // Rows 1 and 2 from the top, columns 2 to 5.
+        assert_eq!(raster.pixel(2, 1), [255, 0, 0, 255]);
+        assert_eq!(raster.pixel(5, 2), [255, 0, 0, 255]);
+        assert_eq!(raster.pixel(1, 1), CLEAR);
+        assert_eq!(raster.pixel(2, 3), CLEAR);
+    }
+
+    #[test]
+    fn blend_in_linear_space() {
+        let mut app = app();
+        // Spawned out of order; depth decides.
+        let white = Color::rgba_linear(1.0, 1.0, 1.0, 0.25);
+        quad(&mut app, Vec2::splat(4.0), Vec2::splat(8.0), 1.0e-4, white);
+        quad(
+            &mut app,
+            Vec2::splat(4.0),
+            Vec2::splat(8.0),
+            0.0,
+            Color::BLACK,
+        );
+        let raster = rasterize(&mut app);
+        // Linear 0.25 is 137 in sRGB.
+        assert_eq!(raster.pixel(4, 4), [137, 137, 137, 255]);
+    }
+
+    #[test]
+    fn clip() {
+        let mut app = app();
+        app.world.spawn().insert_bundle((
+            PietNode::default(),
+            Node {
+                size: Vec2::splat(8.0),
+            },
+            Transform::from_xyz(4.0, 4.0, 0.0),
+            UiColor(Color::GREEN),
+            CalculatedClip {
+                clip: Rect {
+                    min: Vec2::new(0.0, 0.0),
+                    max: Vec2::new(2.0, 8.0),
+                },
+            },
+        ));
+        let raster = rasterize(&mut app);
+        assert_eq!(raster.pixel(1, 4), [0, 255, 0, 255]);
+        assert_eq!(raster.pixel(2, 4), CLEAR);
+    }
+
+    #[test]
+    fn glyph() {
+        let mut app = app();
+        // A 2x2 glyph, opaque on the left and clear on the right.
+        let data = [[255, 255, 255, 255], [255, 255, 255, 0]]
+            .repeat(2)
+            .concat();
+        let image = BevyImage::new(
+            Extent3d {
+                width: 2,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        let texture = app.world.resource_mut::<Assets<BevyImage>>().add(image);
+        let mut atlas = TextureAtlas::new_empty(texture, Vec2::splat(2.0));
+        let glyph_index = atlas.add_texture(Rect {
+            min: Vec2::ZERO,
+            max: Vec2::splat(2.0),
+        });
+        let texture_atlas = app.world.resource_mut::<Assets<TextureAtlas>>().add(atlas);
+
+        let color = Color::rgb(0.0, 0.0, 0.4);
+        app.world.spawn().insert_bundle((
+            PietNode::default(),
+            Node {
+                size: Vec2::splat(2.0),
+            },
+            Transform::from_xyz(4.0, 4.0, 0.0),
+            bevy::text::Text {
+                sections: vec![TextSection {
+                    value: "a".into(),
+                    style: TextStyle {
+                        font: Default::default(),
+                        font_size: 2.0,
+                        color,
+                    },
+                }],
+                alignment: Default::default(),
+            },
+            TextLayoutInfo {
+                glyphs: vec![PositionedGlyph {
+                    // The glyph's center, from the node's lower left.
+                    position: Vec2::splat(1.0),
+                    size: Vec2::splat(2.0),
+                    atlas_info: GlyphAtlasInfo {
+                        texture_atlas,
+                        glyph_index,
+                    },
+                    section_index: 0,
+                    byte_index: 0,
+                }],
+                size: Vec2::splat(2.0),
+            },
+        ));
+        let raster = rasterize(&mut app);
+        // The section color survives the round trip through linear.
+        assert_eq!(raster.pixel(3, 3), [0, 0, 102, 255]);
+        assert_eq!(raster.pixel(3, 4), [0, 0, 102, 255]);
+        assert_eq!(raster.pixel(4, 3), CLEAR);
+        assert_eq!(raster.pixel(2, 3), CLEAR);
+    }
+}